@@ -0,0 +1,265 @@
+use std::cell::Cell;
+use std::char;
+use std::io::{Error, Read};
+use std::rc::Rc;
+use std::str;
+
+/// Character encoding of an html body, as far as the token inserter is concerned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    AsciiCompatible,        //markup can be parsed byte by byte
+    Utf16(Option<Endian>), //endianness if declared, the byte order mark winning anyway
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+// charsets in which ascii characters are encoded as themselves. In gbk, big5 and shift_jis, the
+// trail byte of a multibyte character can be an ascii letter or one of @[\]^_`{|}~, but never a
+// byte meaningful to the markup parser such as <, >, =, /, quotes or whitespace
+const ASCII_COMPATIBLE: &[&str] = &[
+    "utf-8", "utf8", "us-ascii", "ascii", "latin1", "l1", "gbk", "gb2312", "gb18030", "big5",
+    "big5-hkscs", "shift_jis", "sjis", "x-sjis", "windows-31j", "macintosh", "tis-620", "ibm866",
+];
+const ASCII_COMPATIBLE_PREFIXES: &[&str] = &["iso-8859-", "iso8859-", "windows-", "cp125", "koi8-", "euc-"];
+
+impl Charset {
+    /// Get the charset of a body from the charset parameter of its Content-Type, or None if it
+    /// is not supported
+    pub fn from_name(name: Option<&str>) -> Option<Self> {
+        let name = match name {
+            Some(name) => name.trim().trim_matches('"').to_ascii_lowercase(),
+            None => return Some(Charset::AsciiCompatible), //most likely utf-8, or declared in a meta
+        };
+        match &name[..] {
+            "utf-16" => Some(Charset::Utf16(None)),
+            "utf-16le" => Some(Charset::Utf16(Some(Endian::Little))),
+            "utf-16be" => Some(Charset::Utf16(Some(Endian::Big))),
+            name if ASCII_COMPATIBLE.contains(&name)
+                || ASCII_COMPATIBLE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) =>
+            {
+                Some(Charset::AsciiCompatible)
+            }
+            _ => None, //utf-32, iso-2022-jp, utf-7...
+        }
+    }
+
+    /// Decode a body to an ascii compatible encoding, rewrite it, then encode it back
+    pub fn transcode<'a, F>(self, body: Box<dyn Read + 'a>, rewrite: F) -> Box<dyn Read + 'a>
+    where
+        F: FnOnce(Box<dyn Read + 'a>) -> Box<dyn Read + 'a>,
+    {
+        match self {
+            Charset::AsciiCompatible => rewrite(body),
+            Charset::Utf16(declared) => {
+                let endian = Rc::new(Cell::new(declared.unwrap_or(Endian::Little)));
+                let decoded = Utf16Decoder {
+                    underlying: body,
+                    endian: endian.clone(),
+                    sniffed: false,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    eof: false,
+                };
+                Box::new(Utf16Encoder {
+                    underlying: rewrite(Box::new(decoded)),
+                    endian,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    eof: false,
+                })
+            }
+        }
+    }
+}
+
+// copy as much pending output as possible into buf
+fn drain_into(output: &mut Vec<u8>, buf: &mut [u8]) -> usize {
+    let len = output.len().min(buf.len());
+    buf[..len].copy_from_slice(&output[..len]);
+    output.drain(..len);
+    len
+}
+
+// read the next chunk of underlying into input, return false at the end of the stream
+fn fill(underlying: &mut dyn Read, input: &mut Vec<u8>) -> Result<bool, Error> {
+    let mut chunk = [0; 4096];
+    let len = underlying.read(&mut chunk)?;
+    input.extend_from_slice(&chunk[..len]);
+    Ok(len > 0)
+}
+
+/// Decode utf-16 into utf-8
+struct Utf16Decoder<'a> {
+    underlying: Box<dyn Read + 'a>,
+    endian: Rc<Cell<Endian>>, //shared with the encoder, so it encode back with the same byte order
+    sniffed: bool,            //whether the byte order mark was looked for
+    input: Vec<u8>,
+    output: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a> Utf16Decoder<'a> {
+    fn decode(&mut self) {
+        if !self.sniffed && (self.input.len() >= 2 || self.eof) {
+            match self.input.get(..2) {
+                Some([0xFF, 0xFE]) => self.endian.set(Endian::Little),
+                Some([0xFE, 0xFF]) => self.endian.set(Endian::Big),
+                _ => {}
+            } //the mark itself is decoded as U+FEFF, so it's encoded back
+            self.sniffed = true;
+        }
+        if !self.sniffed {
+            return;
+        }
+
+        let endian = self.endian.get();
+        let mut units: Vec<u16> = self
+            .input
+            .chunks_exact(2)
+            .map(|unit| match endian {
+                Endian::Little => u16::from_le_bytes([unit[0], unit[1]]),
+                Endian::Big => u16::from_be_bytes([unit[0], unit[1]]),
+            })
+            .collect();
+        if !self.eof && units.last().map_or(false, |&unit| (0xD800..0xDC00).contains(&unit)) {
+            units.pop();
+        } //the low surrogate will come with next chunk
+        self.input.drain(..units.len() * 2);
+
+        let mut utf8 = [0; 4];
+        for c in char::decode_utf16(units) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            self.output.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+        }
+        if self.eof && !self.input.is_empty() {
+            self.input.clear();
+            self.output.extend_from_slice("\u{FFFD}".as_bytes());
+        } //odd number of bytes
+    }
+}
+
+impl<'a> Read for Utf16Decoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.output.is_empty() && !(self.eof && self.input.is_empty()) {
+            self.eof = !fill(&mut *self.underlying, &mut self.input)?;
+            self.decode();
+        }
+        Ok(drain_into(&mut self.output, buf))
+    }
+}
+
+/// Encode utf-8 into utf-16
+struct Utf16Encoder<'a> {
+    underlying: Box<dyn Read + 'a>,
+    endian: Rc<Cell<Endian>>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a> Utf16Encoder<'a> {
+    fn push_str(&mut self, s: &str) {
+        let endian = self.endian.get();
+        for unit in s.encode_utf16() {
+            match endian {
+                Endian::Little => self.output.extend_from_slice(&unit.to_le_bytes()),
+                Endian::Big => self.output.extend_from_slice(&unit.to_be_bytes()),
+            }
+        }
+    }
+
+    fn encode(&mut self) {
+        let mut pos = 0;
+        loop {
+            match str::from_utf8(&self.input[pos..]) {
+                Ok(s) => {
+                    let s = s.to_owned();
+                    self.push_str(&s);
+                    pos = self.input.len();
+                    break;
+                }
+                Err(e) => {
+                    let valid = pos + e.valid_up_to();
+                    let s = str::from_utf8(&self.input[pos..valid]).unwrap_or_default().to_owned();
+                    self.push_str(&s);
+                    match e.error_len() {
+                        Some(len) => {
+                            self.push_str("\u{FFFD}");
+                            pos = valid + len;
+                        }
+                        None if self.eof => {
+                            self.push_str("\u{FFFD}");
+                            pos = self.input.len();
+                            break;
+                        }
+                        None => {
+                            pos = valid;
+                            break;
+                        } //the end of this character will come with next chunk
+                    }
+                }
+            }
+        }
+        self.input.drain(..pos);
+    }
+}
+
+impl<'a> Read for Utf16Encoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.output.is_empty() && !(self.eof && self.input.is_empty()) {
+            self.eof = !fill(&mut *self.underlying, &mut self.input)?;
+            self.encode();
+        }
+        Ok(drain_into(&mut self.output, buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use charset::{Charset, Endian};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Charset::from_name(None), Some(Charset::AsciiCompatible));
+        assert_eq!(Charset::from_name(Some("UTF-8")), Some(Charset::AsciiCompatible));
+        assert_eq!(Charset::from_name(Some("\"iso-8859-15\"")), Some(Charset::AsciiCompatible));
+        assert_eq!(Charset::from_name(Some("Shift_JIS")), Some(Charset::AsciiCompatible));
+        assert_eq!(Charset::from_name(Some("utf-16")), Some(Charset::Utf16(None)));
+        assert_eq!(Charset::from_name(Some("UTF-16BE")), Some(Charset::Utf16(Some(Endian::Big))));
+        assert_eq!(Charset::from_name(Some("utf-32")), None);
+        assert_eq!(Charset::from_name(Some("iso-2022-jp")), None);
+    }
+
+    #[test]
+    fn test_utf16_round_trip() {
+        let text = "<p>سلام 🦀</p>";
+        let le: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_le_bytes().to_vec()).collect();
+        let be: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_be_bytes().to_vec()).collect();
+        let with_bom = [&[0xFE, 0xFF][..], &be].concat();
+        let cases: &[(&[u8], Charset)] = &[
+            (&le, Charset::Utf16(None)),
+            (&be, Charset::Utf16(Some(Endian::Big))),
+            (&with_bom, Charset::Utf16(Some(Endian::Little))), //byte order mark wins
+        ];
+        for &(data, charset) in cases {
+            let mut decoded = None;
+            let mut res = Vec::new();
+            charset
+                .transcode(Box::new(Cursor::new(data)), |mut body| {
+                    let mut utf8 = String::new();
+                    body.read_to_string(&mut utf8).unwrap();
+                    decoded = Some(utf8.clone());
+                    Box::new(Cursor::new(utf8.into_bytes()))
+                })
+                .read_to_end(&mut res)
+                .unwrap();
+            assert_eq!(decoded.unwrap().trim_start_matches('\u{FEFF}'), text);
+            assert_eq!(res, data);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+#[cfg(feature = "compression")]
+use flate2::{
+    read::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder},
+    Compression,
+};
+use std::io::Read;
+
+/// Content coding of a response body the token inserter can rewrite
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    #[cfg(feature = "compression")]
+    Gzip,
+    #[cfg(feature = "compression")]
+    Deflate,
+}
+
+impl Encoding {
+    /// Get the encoding of a body from the value of its Content-Encoding header, or None if it is
+    /// not supported
+    pub fn from_header(value: Option<&str>) -> Option<Self> {
+        let value = match value {
+            Some(value) => value.trim().to_ascii_lowercase(),
+            None => return Some(Encoding::Identity),
+        };
+        match &value[..] {
+            "" | "identity" => Some(Encoding::Identity),
+            #[cfg(feature = "compression")]
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            #[cfg(feature = "compression")]
+            "deflate" => Some(Encoding::Deflate), //zlib format, as defined by http
+            _ => None, //other codings, or several codings applied one after the other
+        }
+    }
+
+    /// Wrap an encoded body into a reader of its decoded content
+    pub fn decode<'a>(self, body: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match self {
+            Encoding::Identity => body,
+            #[cfg(feature = "compression")]
+            Encoding::Gzip => Box::new(GzDecoder::new(body)),
+            #[cfg(feature = "compression")]
+            Encoding::Deflate => Box::new(ZlibDecoder::new(body)),
+        }
+    }
+
+    /// Wrap a decoded body into a reader of its content encoded again
+    pub fn encode<'a>(self, body: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match self {
+            Encoding::Identity => body,
+            #[cfg(feature = "compression")]
+            Encoding::Gzip => Box::new(GzEncoder::new(body, Compression::default())),
+            #[cfg(feature = "compression")]
+            Encoding::Deflate => Box::new(ZlibEncoder::new(body, Compression::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compression::Encoding;
+    #[cfg(feature = "compression")]
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(Encoding::from_header(None), Some(Encoding::Identity));
+        assert_eq!(Encoding::from_header(Some(" Identity ")), Some(Encoding::Identity));
+        assert_eq!(Encoding::from_header(Some("br")), None);
+        assert_eq!(Encoding::from_header(Some("gzip, br")), None);
+        #[cfg(feature = "compression")]
+        {
+            assert_eq!(Encoding::from_header(Some("GZIP")), Some(Encoding::Gzip));
+            assert_eq!(Encoding::from_header(Some("deflate")), Some(Encoding::Deflate));
+        }
+        #[cfg(not(feature = "compression"))]
+        assert_eq!(Encoding::from_header(Some("gzip")), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_round_trip() {
+        let data = b"<form method=post></form>".repeat(100);
+        for &encoding in &[Encoding::Gzip, Encoding::Deflate] {
+            let mut encoded = Vec::new();
+            encoding
+                .encode(Box::new(Cursor::new(&data[..])))
+                .read_to_end(&mut encoded)
+                .unwrap();
+            assert!(encoded.len() < data.len());
+            let mut decoded = Vec::new();
+            encoding
+                .decode(Box::new(Cursor::new(encoded)))
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+}
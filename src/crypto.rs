@@ -1,130 +1,466 @@
-use ring::aead::{CHACHA20_POLY1305, OpeningKey, SealingKey, UnboundKey, BoundKey, Nonce, NonceSequence, Aad};
+use ring::aead::{self, AES_256_GCM, CHACHA20_POLY1305, OpeningKey, SealingKey, UnboundKey, BoundKey, Nonce, NonceSequence, Aad};
 use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
 use ring::error::Unspecified;
+use ring::{hkdf, hmac};
 use ring::rand::{SecureRandom, SystemRandom};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
 use std::time::SystemTime;
 
 
 const KEYSIZE: usize = 32;
+const ALGORITHM_SIZE: usize = 1;
 const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = ALGORITHM_SIZE + NONCE_SIZE;
 const DATE_SIZE: usize = 8;
 const TAG_SIZE: usize = 64;
+const COMPACT_TAG_SIZE: usize = 16;
+// set in the first byte of compact tokens and cookies, along with the algorithm identifier
+const COMPACT_FLAG: u8 = 0x80;
 const SIG_SIZE: usize = 16;
-const TOKEN_SIZE: usize = NONCE_SIZE + TAG_SIZE + SIG_SIZE;
-const COOKIE_SIZE: usize = NONCE_SIZE + DATE_SIZE + TAG_SIZE + SIG_SIZE;
+// size of the digest of the session (and privilege) a server-side stored token is bound to
+const BINDING_SIZE: usize = 16;
+const TOKEN_SIZE: usize = HEADER_SIZE + DATE_SIZE + TAG_SIZE + SIG_SIZE;
+const COOKIE_SIZE: usize = HEADER_SIZE + 2 * DATE_SIZE + TAG_SIZE + SIG_SIZE;
+/// Size of the buffer needed by `generate_token_pair`, which fit both formats
+pub const PAIR_SIZE: usize = TOKEN_SIZE + COOKIE_SIZE;
+
+/// Algorithm used to authenticate tokens and cookies.
+///
+/// The algorithm is recorded in each token and cookie, which are then only accepted when checked
+/// with the same algorithm. Changing it invalidates tokens issued before.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// ChaCha20-Poly1305, the default. Fast everywhere, including on cpus without AES
+    /// instructions.
+    #[default]
+    ChaCha20Poly1305,
+    /// AES-256-GCM, for deployments requiring FIPS approved algorithms.
+    Aes256Gcm,
+    /// HMAC-SHA256, truncated to 128 bits. Tokens and cookies are signed but not encrypted, as
+    /// they only carry random values and dates, which need no secrecy. This is not a cheaper
+    /// mode: issuing and checking tokens is slower than with the other algorithms, and tokens are
+    /// longer. It only exists to be easier to audit, or to check tokens with nothing but
+    /// HMAC-SHA256.
+    HmacSha256,
+}
+
+impl Algorithm {
+    // first byte of tokens and cookies
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::ChaCha20Poly1305 => 1,
+            Algorithm::Aes256Gcm => 2,
+            Algorithm::HmacSha256 => 3,
+        }
+    }
+
+    // None for signature algorithms
+    fn aead(self) -> Option<&'static aead::Algorithm> {
+        match self {
+            Algorithm::ChaCha20Poly1305 => Some(&CHACHA20_POLY1305),
+            Algorithm::Aes256Gcm => Some(&AES_256_GCM),
+            Algorithm::HmacSha256 => None,
+        }
+    }
+}
 
 
+#[derive(Clone)]
 pub struct CsrfProtection {
     aead_key: [u8; KEYSIZE],
+    hmac_key: hmac::Key,
+    algorithm: Algorithm,
+    compact: bool,
+    max_lifetime: Option<u64>,
 }
 
 impl CsrfProtection {
-    pub fn from_key(aead_key: [u8; KEYSIZE]) -> Self {
-        CsrfProtection { aead_key }
+    // a subkey is derived from the secret for each kind of primitive, so the same key is never
+    // used with both
+    pub fn from_key(secret: [u8; KEYSIZE]) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&secret);
+        let mut aead_key = [0; KEYSIZE];
+        prk.expand(&[b"csrf aead"], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut aead_key))
+            .expect("HKDF-SHA256 output fits a 32 bytes key");
+        let hmac_key = prk
+            .expand(&[b"csrf hmac"], hmac::HMAC_SHA256)
+            .map(hmac::Key::from)
+            .expect("HKDF-SHA256 output fits a 32 bytes key");
+        CsrfProtection {
+            aead_key,
+            hmac_key,
+            algorithm: Algorithm::default(),
+            compact: false,
+            max_lifetime: None,
+        }
+    }
+
+    pub fn with_algorithm(self, algorithm: Algorithm) -> Self {
+        CsrfProtection { algorithm, ..self }
+    }
+
+    // compact tokens carry a 128 bits random value instead of 512, and are not masked when they
+    // are encrypted, as sealing them with a fresh nonce already make them different in each
+    // response
+    pub fn with_compact(self, compact: bool) -> Self {
+        CsrfProtection { compact, ..self }
     }
 
-    pub fn parse_cookie<'a>(&self, cookie: &'a mut [u8]) -> Result<CsrfCookie<'a>, CsrfError> {
-        if cookie.len() < NONCE_SIZE {
-            return Err(CsrfError::ValidationError);// cookie is too short to be valid
+    // values first issued more than max_lifetime seconds ago are rotated, and no longer accepted
+    pub fn with_max_lifetime(self, max_lifetime: Option<u64>) -> Self {
+        CsrfProtection { max_lifetime, ..self }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn format_id(&self) -> u8 {
+        if self.compact {
+            self.algorithm.id() | COMPACT_FLAG
+        } else {
+            self.algorithm.id()
         }
-        let (nonce, token) = cookie.split_at_mut(NONCE_SIZE);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::UnknownError)?;
-        let nonce = OneNonceSequence::new(Nonce::try_assume_unique_for_key(nonce).map_err(|_| CsrfError::ValidationError)?);
-        let mut key = OpeningKey::new(unbound_key, nonce);
-        let token = key.open_in_place(Aad::from(&[]), token).map_err(|_| CsrfError::ValidationError)?;
-        if token.len() < DATE_SIZE {// shorter than a timestamp, must be invalid
+    }
+
+    // signed tokens carry their value in clear, so they are always masked
+    fn masked(&self) -> bool {
+        !self.compact || self.algorithm.aead().is_none()
+    }
+
+    fn tag_size(&self) -> usize {
+        if self.compact {
+            COMPACT_TAG_SIZE
+        } else {
+            TAG_SIZE
+        }
+    }
+
+    fn token_size(&self) -> usize {
+        HEADER_SIZE + DATE_SIZE + self.tag_size() + SIG_SIZE
+    }
+
+    fn cookie_size(&self) -> usize {
+        HEADER_SIZE + 2 * DATE_SIZE + self.tag_size() + SIG_SIZE
+    }
+
+    // split the format identifier and nonce from a sealed value, checking it was sealed with
+    // the algorithm and format in use
+    fn split_header<'a>(&self, sealed: &'a mut [u8]) -> Result<(&'a [u8], &'a mut [u8]), CsrfError> {
+        if sealed.len() < HEADER_SIZE || sealed[0] != self.format_id() {
             return Err(CsrfError::ValidationError);
         }
-        let mut expires = [0;DATE_SIZE];
-        expires.copy_from_slice(&token[..DATE_SIZE]);
-        let expires = u64::from_be_bytes(expires);
-        let token = &token[DATE_SIZE..];
+        let (header, sealed) = sealed.split_at_mut(HEADER_SIZE);
+        Ok((&header[ALGORITHM_SIZE..], sealed))
+    }
+
+    pub fn parse_cookie<'a>(&self, cookie: &'a mut [u8], session: &[u8]) -> Result<CsrfCookie<'a>, CsrfError> {
+        let (nonce, token) = self.split_header(cookie)?;
+        let token = self.open_in_place(nonce, token, session)?;
+        if token.len() < 2 * DATE_SIZE {// shorter than two timestamps, must be invalid
+            return Err(CsrfError::ValidationError);
+        }
+        let (expires, token) = token.split_at(DATE_SIZE);
+        let (issued, token) = token.split_at(DATE_SIZE);
         Ok(CsrfCookie{
-            token,
-            expires,
+            token: Cow::Borrowed(token),
+            expires: read_date(expires),
+            issued: read_date(issued),
         })
     }
 
-    pub fn parse_token<'a>(&self, token: &'a mut [u8]) -> Result<CsrfToken<'a>, CsrfError> {
-        if token.len() < NONCE_SIZE {
-            return Err(CsrfError::ValidationError);// cookie is too short to be valid
+    pub fn parse_token<'a>(&self, token: &'a mut [u8], session: &[u8]) -> Result<CsrfToken<'a>, CsrfError> {
+        let token = if !self.masked() {
+            token
+        } else {
+            if token.len() % 2 != 0 {
+                return Err(CsrfError::ValidationError);// not a masked token
+            }
+            let (pad, token) = token.split_at_mut(token.len() / 2);
+            for (byte, pad) in token.iter_mut().zip(pad.iter()) {
+                *byte ^= pad;
+            } //unmask the token, see mask_token
+            token
+        };
+        let (id, token) = self.split_header(token)?;
+        let token = self.open_in_place(id, token, session)?;
+        if token.len() < DATE_SIZE {// shorter than a timestamp, must be invalid
+            return Err(CsrfError::ValidationError);
         }
-        let (nonce, token) = token.split_at_mut(NONCE_SIZE);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::ValidationError)?;
-        let nonce = OneNonceSequence::new(Nonce::try_assume_unique_for_key(nonce).map_err(|_| CsrfError::ValidationError)?);
-        let mut key = OpeningKey::new(unbound_key, nonce);
-        // let token = open_in_place(&key, nonce, &[], 0, token).map_err(|_| CsrfError::ValidationError)?;
-        let token = key.open_in_place(Aad::from(&[]), token).map_err(|_| CsrfError::ValidationError)?;
+        let (issued, token) = token.split_at(DATE_SIZE);
         Ok(CsrfToken{
+            id,
             token,
+            issued: read_date(issued),
         })
     }
 
     pub fn verify_token_pair(&self, token: &CsrfToken, cookie: &CsrfCookie) -> bool {
-        let token_ok = verify_slices_are_equal(token.token,cookie.token).is_ok();
-        let not_expired = cookie.time_left() > 0; 
+        let token_ok = verify_slices_are_equal(token.token, &cookie.token).is_ok();
 
-        token_ok && not_expired
+        token_ok && self.is_live(cookie)
     }
 
-    pub fn generate_token_pair<'a>(&self, previous_token: Option<CsrfCookie>, ttl_seconds: u64, source_buffer: &'a mut[u8; TOKEN_SIZE + COOKIE_SIZE]) -> Result<(&'a[u8], &'a[u8]), CsrfError> {
-        let (token, cookie) = source_buffer.split_at_mut(TOKEN_SIZE);
-        let expire = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() + ttl_seconds).map_err(|_| CsrfError::UnknownError)?;
-        cookie[NONCE_SIZE..DATE_SIZE+NONCE_SIZE].copy_from_slice(&expire.to_be_bytes());
-        
-        let rand = SystemRandom::new();
-        if let Some(previous_token) = previous_token {
-            cookie[NONCE_SIZE+DATE_SIZE..TAG_SIZE+NONCE_SIZE+DATE_SIZE].copy_from_slice(previous_token.token);
-            token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE].copy_from_slice(previous_token.token);
-        } else {
-            rand.fill(&mut token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE]).map_err(|_| CsrfError::UnknownError)?;
-            cookie[NONCE_SIZE+DATE_SIZE..TAG_SIZE+NONCE_SIZE+DATE_SIZE].copy_from_slice(&token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE]);
+    // whether a cookie is neither expired nor past its maximum lifetime
+    pub fn is_live(&self, cookie: &CsrfCookie) -> bool {
+        let in_lifetime = self.max_lifetime.map_or(true, |lifetime| {
+            now().map_or(false, |now| cookie.issued.saturating_add(lifetime) > now)
+        });
+        cookie.time_left() > 0 && in_lifetime
+    }
+
+    // get the cookie to issue next: the previous one expiring `ttl_seconds` from now, unless it is
+    // no longer live and must be rotated. Cookies never outlive their maximum lifetime
+    pub fn renew<'a>(&self, previous: Option<CsrfCookie<'a>>, ttl_seconds: u64) -> Result<CsrfCookie<'a>, CsrfError> {
+        let now = now()?;
+        let (token, issued) = match previous.filter(|c| c.token.len() == self.tag_size() && self.is_live(c)) {
+            Some(previous) => (previous.token, previous.issued),
+            None => (Cow::Owned(self.generate_tag()?), now),
+        };
+        let expires = match self.max_lifetime {
+            Some(lifetime) => (now + ttl_seconds).min(issued.saturating_add(lifetime)),
+            None => now + ttl_seconds,
+        };
+        Ok(CsrfCookie { token, expires, issued })
+    }
+
+    pub fn generate_token_pair<'a>(&self, next: &CsrfCookie, session: &[u8], source_buffer: &'a mut[u8; PAIR_SIZE]) -> Result<(&'a[u8], &'a[u8]), CsrfError> {
+        let tag_size = self.tag_size();
+        if next.token.len() != tag_size {
+            return Err(CsrfError::ValidationError);
         }
-        
+        let (token, cookie) = source_buffer.split_at_mut(self.token_size());
+        let cookie = &mut cookie[..self.cookie_size()];
+        cookie[HEADER_SIZE..DATE_SIZE+HEADER_SIZE].copy_from_slice(&next.expires.to_be_bytes());
+        cookie[HEADER_SIZE+DATE_SIZE..2*DATE_SIZE+HEADER_SIZE].copy_from_slice(&next.issued.to_be_bytes());
+        cookie[HEADER_SIZE+2*DATE_SIZE..tag_size+HEADER_SIZE+2*DATE_SIZE].copy_from_slice(&next.token);
+        token[HEADER_SIZE..DATE_SIZE+HEADER_SIZE].copy_from_slice(&now()?.to_be_bytes());
+        token[HEADER_SIZE+DATE_SIZE..tag_size+HEADER_SIZE+DATE_SIZE].copy_from_slice(&next.token);
+
+        let rand = SystemRandom::new();
         let mut nonce = [0;NONCE_SIZE];
-        
+
         rand.fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
-        self.seal_in_place(nonce, token, TOKEN_SIZE)?;
+        self.seal_in_place(nonce, token, session)?;
 
         rand.fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
-        self.seal_in_place(nonce, cookie, COOKIE_SIZE)?;
+        self.seal_in_place(nonce, cookie, session)?;
 
         Ok((token, cookie))
     }
 
-    fn seal_in_place(&self, nonce: [u8; NONCE_SIZE], in_out: &mut [u8], in_out_size: usize) -> Result<(), CsrfError> {
-        in_out[..NONCE_SIZE].copy_from_slice(&nonce);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::ValidationError)?;
+    // seal a single token for an already issued pair, authenticating `aad` along with it
+    pub fn seal_token(&self, tag: &[u8], aad: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        if tag.len() != self.tag_size() {
+            return Err(CsrfError::ValidationError);
+        }
+        let mut token = vec![0; self.token_size()];
+        token[HEADER_SIZE..DATE_SIZE+HEADER_SIZE].copy_from_slice(&now()?.to_be_bytes());
+        token[HEADER_SIZE+DATE_SIZE..tag.len()+HEADER_SIZE+DATE_SIZE].copy_from_slice(tag);
+        let mut nonce = [0;NONCE_SIZE];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
+        self.seal_in_place(nonce, &mut token, aad)?;
+        Ok(token)
+    }
+
+    // xor a token with a random pad, prepended to the result. Masking tokens each time they are
+    // sent prevent compression based attacks like BREACH from recovering them across responses.
+    // Compact encrypted tokens are returned as is
+    pub fn mask_token(&self, token: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        if !self.masked() {
+            return Ok(token.to_vec());
+        }
+        let mut masked = vec![0; 2 * token.len()];
+        SystemRandom::new().fill(&mut masked[..token.len()]).map_err(|_| CsrfError::UnknownError)?;
+        let (pad, value) = masked.split_at_mut(token.len());
+        for ((byte, pad), token) in value.iter_mut().zip(pad.iter()).zip(token) {
+            *byte = pad ^ token;
+        }
+        Ok(masked)
+    }
+
+    pub fn generate_tag(&self) -> Result<Vec<u8>, CsrfError> {
+        let mut tag = vec![0; self.tag_size()];
+        SystemRandom::new().fill(&mut tag).map_err(|_| CsrfError::UnknownError)?;
+        Ok(tag)
+    }
+
+    // session is authenticated as associated data, so a pair sealed for one session can't be
+    // opened under another one
+    fn seal_in_place(&self, nonce: [u8; NONCE_SIZE], in_out: &mut [u8], session: &[u8]) -> Result<(), CsrfError> {
+        in_out[0] = self.format_id();
+        in_out[ALGORITHM_SIZE..HEADER_SIZE].copy_from_slice(&nonce);
+        let algorithm = match self.algorithm.aead() {
+            Some(algorithm) => algorithm,
+            None => {
+                let (payload, sig) = in_out.split_at_mut(in_out.len() - SIG_SIZE);
+                let tag = self.sign(&nonce, &payload[HEADER_SIZE..], session);
+                sig.copy_from_slice(&tag.as_ref()[..SIG_SIZE]);
+                return Ok(());
+            } //signed in place, the payload stays in clear
+        };
+        let unbound_key = UnboundKey::new(algorithm, &self.aead_key).map_err(|_| CsrfError::ValidationError)?;
         let nonce_sequence = OneNonceSequence::new(Nonce::assume_unique_for_key(nonce));
         let mut key = SealingKey::new(unbound_key, nonce_sequence);
-        let mut io = Vec::from(&in_out[NONCE_SIZE..(in_out_size - SIG_SIZE)]);
-        key.seal_in_place_append_tag(Aad::from(&[]), &mut io).map_err(|_| CsrfError::UnknownError)?;
-        in_out[NONCE_SIZE..].copy_from_slice(&io);
+        let mut io = Vec::from(&in_out[HEADER_SIZE..(in_out.len() - SIG_SIZE)]);
+        key.seal_in_place_append_tag(Aad::from(session), &mut io).map_err(|_| CsrfError::UnknownError)?;
+        in_out[HEADER_SIZE..].copy_from_slice(&io);
 
         Ok(())
     }
+
+    // authenticate and decrypt a sealed value in place, returning its payload
+    fn open_in_place<'a>(&self, nonce: &[u8], sealed: &'a mut [u8], session: &[u8]) -> Result<&'a [u8], CsrfError> {
+        let algorithm = match self.algorithm.aead() {
+            Some(algorithm) => algorithm,
+            None => {
+                if sealed.len() < SIG_SIZE {
+                    return Err(CsrfError::ValidationError);
+                }
+                let (payload, sig) = sealed.split_at(sealed.len() - SIG_SIZE);
+                let tag = self.sign(nonce, payload, session);
+                return verify_slices_are_equal(&tag.as_ref()[..SIG_SIZE], sig)
+                    .map(|_| payload)
+                    .map_err(|_| CsrfError::ValidationError);
+            }
+        };
+        let unbound_key = UnboundKey::new(algorithm, &self.aead_key).map_err(|_| CsrfError::UnknownError)?;
+        let nonce = OneNonceSequence::new(Nonce::try_assume_unique_for_key(nonce).map_err(|_| CsrfError::ValidationError)?);
+        let mut key = OpeningKey::new(unbound_key, nonce);
+        key.open_in_place(Aad::from(session), sealed).map(|payload| &*payload).map_err(|_| CsrfError::ValidationError)
+    }
+
+    // signature of a value, covering its format and nonce along with the payload and session
+    fn sign(&self, nonce: &[u8], payload: &[u8], session: &[u8]) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.hmac_key);
+        context.update(&[self.format_id()]);
+        context.update(nonce);
+        context.update(&(session.len() as u64).to_be_bytes());
+        context.update(session);
+        context.update(payload);
+        context.sign()
+    }
 }
 
 pub struct CsrfToken<'a> {
+    id: &'a[u8],
     token: &'a[u8],
+    issued: u64,
+}
+
+impl<'a> CsrfToken<'a> {
+    // used to check an already parsed token
+    pub fn new(id: &'a [u8], token: &'a [u8], issued: u64) -> Self {
+        CsrfToken { id, token, issued }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        self.token
+    }
+
+    // each token is sealed with a fresh random nonce, which is then unique to this token
+    pub fn id(&self) -> &[u8] {
+        self.id
+    }
+
+    // date at which this token was sealed, unlike the value it carries which may be older
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
 }
 
 pub struct CsrfCookie<'a> {
-    token: &'a[u8],
-    expires: u64
+    token: Cow<'a, [u8]>,
+    expires: u64,
+    issued: u64,
 }
 
 impl<'a> CsrfCookie<'a> {
+    pub fn new(token: &'a [u8], expires: u64, issued: u64) -> Self {
+        CsrfCookie { token: Cow::Borrowed(token), expires, issued }
+    }
+
+    // used to build a cookie from a server-side stored token, prefixed with its issue date and
+    // the digest of what it is bound to, so it's rotated when the privilege of a session change
+    pub fn from_stored(stored: &'a [u8], expires: u64, binding: &[u8]) -> Option<Self> {
+        if stored.len() < DATE_SIZE + BINDING_SIZE {
+            return None;
+        }
+        let (issued, stored) = stored.split_at(DATE_SIZE);
+        let (digest, token) = stored.split_at(BINDING_SIZE);
+        verify_slices_are_equal(digest, &binding_digest(binding)).ok()?;
+        Some(CsrfCookie::new(token, expires, read_date(issued)))
+    }
+
+    pub fn to_stored(&self, binding: &[u8]) -> Vec<u8> {
+        let mut stored = self.issued.to_be_bytes().to_vec();
+        stored.extend_from_slice(&binding_digest(binding));
+        stored.extend_from_slice(&self.token);
+        stored
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+
+    // date at which the value of this cookie was first issued
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
+
     pub fn time_left(&self) -> u64 {
         SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok().and_then(|now| self.expires.checked_sub(now.as_secs())).unwrap_or(0)
     }
 }
 
+pub fn now() -> Result<u64, CsrfError> {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).map_err(|_| CsrfError::UnknownError)
+}
+
+fn binding_digest(binding: &[u8]) -> [u8; BINDING_SIZE] {
+    let mut res = [0; BINDING_SIZE];
+    res.copy_from_slice(&digest(&SHA256, binding).as_ref()[..BINDING_SIZE]);
+    res
+}
+
+fn read_date(bytes: &[u8]) -> u64 {
+    let mut date = [0; DATE_SIZE];
+    date.copy_from_slice(bytes);
+    u64::from_be_bytes(date)
+}
+
+/// Error while issuing or verifying a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CsrfError {
+    /// The token or cookie is malformed, was forged, was issued for another session, or they
+    /// don't match each other.
     ValidationError,
+    /// The cookie has expired.
+    Expired,
+    /// The system random number generator or clock failed.
     UnknownError,
 }
 
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CsrfError::ValidationError => "invalid csrf token or cookie",
+            CsrfError::Expired => "expired csrf cookie",
+            CsrfError::UnknownError => "failed to issue csrf token",
+        })
+    }
+}
+
+impl Error for CsrfError {}
+
 // from ring's test
 struct OneNonceSequence(Option<Nonce>);
 
@@ -2,7 +2,7 @@ use data_encoding::{BASE64, BASE64URL_NOPAD};
 use ring::rand::{SecureRandom, SystemRandom};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::uri::{Origin, Uri};
-use rocket::http::Cookie;
+use rocket::http::{ContentType, Cookie, SameSite};
 use rocket::http::Method::{self, *};
 use rocket::outcome::Outcome;
 use rocket::response::Body::Sized;
@@ -11,14 +11,21 @@ use std::collections::HashMap;
 use std::env;
 use std::io::{Cursor, Read};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use time::Duration;
 
-use crypto::CsrfProtection;
-use csrf_proxy::CsrfProxy;
-use csrf_token::CsrfToken;
+use charset::Charset;
+use compression::Encoding;
+use crypto::{now, CsrfCookie, CsrfProtection};
+use csrf_proxy::{CsrfInserter, FormMatcher};
+use csrf_token::{form_aad, CsrfToken};
 use path::Path;
-use utils::parse_args;
-use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_FORM_FIELD_MULTIPART};
+use protection::{bind_privilege, Algorithm};
+use rewriter::{ElementHandler, HtmlRewriter};
+use token_store::{MemoryReplayCache, ReplayCache, TokenStore};
+use utils::{normalize_uri, parse_args, resolve_uri};
+use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_FORM_FIELD_MULTIPART, NO_INSERT_HEADER};
 
 /// Builder for [CsrfFairing](struct.CsrfFairing.html)
 ///
@@ -56,12 +63,31 @@ use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_FORM_FIELD_MULTIPART};
 
 pub struct CsrfFairingBuilder {
     duration: u64,
+    max_lifetime: Option<u64>,
     default_target: (String, Method),
     exceptions: Vec<(String, String, Option<Method>)>,
     secret: Option<[u8; 32]>,
+    algorithm: Algorithm,
+    compact: bool,
     auto_insert: bool,
     auto_insert_disable_prefix: Vec<String>,
+    auto_insert_exclusions: Vec<(String, Option<Method>)>,
     auto_insert_max_size: u64,
+    auto_insert_max_sized_body: Option<u64>,
+    session: Option<SessionExtractor>,
+    privilege: Option<SessionExtractor>,
+    store: Option<Arc<dyn TokenStore>>,
+    single_use: Vec<(String, Option<Method>)>,
+    replay_cache: Option<Arc<dyn ReplayCache>>,
+    per_form: bool,
+    origins: Vec<String>,
+    forms: FormMatcher,
+    auto_insert_meta: bool,
+    request_header: Option<String>,
+    response_header: Option<String>,
+    token_cookie: Option<String>,
+    auto_insert_types: Vec<ContentType>,
+    element_handlers: Vec<HandlerFactory>,
 }
 
 impl CsrfFairingBuilder {
@@ -69,22 +95,52 @@ impl CsrfFairingBuilder {
     pub fn new() -> Self {
         CsrfFairingBuilder {
             duration: 60 * 60 * 12,
+            max_lifetime: None,
             default_target: (String::from("/"), Get),
             exceptions: Vec::new(),
             secret: None,
+            algorithm: Algorithm::default(),
+            compact: false,
             auto_insert: true,
             auto_insert_disable_prefix: Vec::new(),
+            auto_insert_exclusions: Vec::new(),
             auto_insert_max_size: 16 * 1024,
+            auto_insert_max_sized_body: None,
+            session: None,
+            privilege: None,
+            store: None,
+            single_use: Vec::new(),
+            replay_cache: None,
+            per_form: false,
+            origins: Vec::new(),
+            forms: FormMatcher::default(),
+            auto_insert_meta: false,
+            request_header: None,
+            response_header: None,
+            token_cookie: None,
+            auto_insert_types: vec![ContentType::HTML],
+            element_handlers: Vec::new(),
         }
     }
 
     /// Set the timeout (in seconds) of CSRF tokens generated by the final Fairing. Default timeout
-    /// is twelve hour.
+    /// is twelve hour. This is an idle timeout: each time a token is issued, the expiration of the
+    /// cookie is pushed back, so tokens of an active client keep the same value until
+    /// [`set_max_lifetime`](#method.set_max_lifetime) is reached.
     pub fn set_timeout(mut self, timeout: u64) -> Self {
         self.duration = timeout;
         self
     }
 
+    /// Set the maximum lifetime (in seconds) of token values. Once a value was first issued this
+    /// long ago, tokens and cookies carrying it are rejected and a new value is issued, regardless
+    /// of the client activity. By default values are only rotated when they reach the
+    /// [timeout](#method.set_timeout) without being used.
+    pub fn set_max_lifetime(mut self, max_lifetime: u64) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
     /// Set the default route when an invalide request is catched, you may add a <uri> as a segment
     /// or a param to get the percent-encoded original target. You can also set the method of the
     /// route to which you choosed to redirect.
@@ -181,6 +237,44 @@ impl CsrfFairingBuilder {
         self
     }
 
+    /// Set the algorithm tokens and cookies are authenticated with. Default is ChaCha20-Poly1305,
+    /// AES-256-GCM may be used where FIPS approved algorithms are required, and HMAC-SHA256 to
+    /// sign tokens without encrypting them, which is slower and only meant to be easier to audit.
+    /// The algorithm is recorded in tokens, so changing it invalidates tokens issued before.
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::{Algorithm, CsrfFairingBuilder};
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_algorithm(Algorithm::Aes256Gcm)
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set if compact tokens and cookies should be issued. Compact tokens carry a 128 bits random
+    /// value instead of 512, and are not masked as they are already sealed with a fresh nonce in
+    /// each response, which makes them about a quarter of the size of default tokens (71
+    /// characters instead of 270), and cookies half of it. Tokens signed with
+    /// [HMAC-SHA256](enum.Algorithm.html#variant.HmacSha256) are still masked, and take 142
+    /// characters. The format is recorded in tokens, so changing it invalidates tokens issued
+    /// before. Default is false.
+    pub fn set_compact_tokens(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
     /// Set if this should modify response to insert tokens automatically in all forms. If true,
     /// this will insert tokens in all forms it encounter, if false, you will have to add them via
     /// [CsrfFairing](struct.CsrfFairing.html), which you may obtain via request guards.
@@ -190,8 +284,50 @@ impl CsrfFairingBuilder {
         self
     }
 
+    /// Set if a `<meta name="csrf-token" content="...">` tag should also be inserted right after
+    /// the `<head>` tag of pages, so scripts can read the token from the DOM. This has no effect
+    /// if auto_insert is set to false. As such a token is not bound to any form, this can't be used
+    /// along with [per-form tokens](#method.set_per_form_tokens). Default is false.
+    pub fn set_auto_insert_meta(mut self, auto_insert_meta: bool) -> Self {
+        self.auto_insert_meta = auto_insert_meta;
+        self
+    }
+
+    /// Set the media types of responses into which tokens are inserted. This has no effect if
+    /// auto_insert is set to false. Types ending with `+xml`, such as `application/xhtml+xml`,
+    /// are parsed with the xml syntax. Responses without a Content-Type are always parsed. Default
+    /// is `text/html` only.
+    ///
+    /// Handlers can also disable insertion for a single response by setting the
+    /// [`NO_INSERT_HEADER`](constant.NO_INSERT_HEADER.html) header, which is never sent to
+    /// clients.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::http::ContentType;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_auto_insert_media_types(vec![
+    ///                     ContentType::HTML,
+    ///                     ContentType::new("application", "xhtml+xml"),
+    ///                 ])
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_auto_insert_media_types(mut self, media_types: Vec<ContentType>) -> Self {
+        self.auto_insert_types = media_types;
+        self
+    }
+
     /// Set prefixs for which this will not try to add tokens in forms. This has no effect if
-    /// auto_insert is set to false. Not having to parse response on paths witch don't need it may
+    /// auto_insert is set to false. Single responses can also be excluded by wrapping them in
+    /// [NoCsrfInsert](struct.NoCsrfInsert.html). Not having to parse response on paths witch don't need it may
     /// improve performances, but not that only html documents are parsed, so it's not usefull to
     /// use it on routes containing only images or stillsheets.
     pub fn set_auto_insert_disable_prefix(mut self, auto_insert_prefix: Vec<String>) -> Self {
@@ -199,15 +335,413 @@ impl CsrfFairingBuilder {
         self
     }
 
-    /// Set the maximum size of a request before it get send chunked. A request will need at most
-    /// this additional memory for the buffer used to parse and tokens into forms. This have no
+    /// Set routes for which this will not try to add tokens in forms. This has no effect if
+    /// auto_insert is set to false. Unlike [`set_auto_insert_disable_prefix`], routes use the same
+    /// syntax as exceptions, see [`set_exceptions`], and match any method if none is given.
+    ///
+    /// [`set_auto_insert_disable_prefix`]: #method.set_auto_insert_disable_prefix
+    /// [`set_exceptions`]: #method.set_exceptions
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_auto_insert_exclusions(vec![
+    ///                     ("/api/<version>/embed/<id..>".to_owned(), None),
+    ///                     ("/search?preview=1".to_owned(), Some(rocket::http::Method::Get)),
+    ///                 ])
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_auto_insert_exclusions(mut self, routes: Vec<(String, Option<Method>)>) -> Self {
+        self.auto_insert_exclusions = routes;
+        self
+    }
+
+    /// Add a handler rewriting the elements of html responses, along with token insertion. The
+    /// function is called for each response to create its handler, which then see every tag of
+    /// the page, see [ElementHandler](trait.ElementHandler.html). Handlers run on the same
+    /// responses as token insertion, even when auto_insert is set to false.
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::{CsrfFairingBuilder, Element, ElementHandler, Rewrite};
+    ///
+    /// struct ExternalLinks;
+    ///
+    /// impl ElementHandler for ExternalLinks {
+    ///     fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+    ///         let external = element.attribute(b"href").map_or(false, |href| href.starts_with(b"http"));
+    ///         if element.name() == b"a" && external {
+    ///             rewrite.add_attribute("rel", "noopener");
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .add_element_handler(|_request| ExternalLinks)
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn add_element_handler<F, H>(mut self, factory: F) -> Self
+    where
+        F: Fn(&Request) -> H + Send + Sync + 'static,
+        H: ElementHandler + 'static,
+    {
+        self.element_handlers.push(Arc::new(move |request| Box::new(factory(request))));
+        self
+    }
+
+    /// Set the maximum size of a request before it get send chunked. Streamed bodies need at most
+    /// this additional memory for the buffer used to parse and tokens into forms, tags and comments
+    /// longer than that being sent unmodified. Sized bodies are handled as described in
+    /// [`set_auto_insert_max_sized_body`](#method.set_auto_insert_max_sized_body). This have no
     /// effect if auto_insert is set to false. Default value is 16Kio
     pub fn set_auto_insert_max_chunk_size(mut self, chunk_size: u64) -> Self {
         self.auto_insert_max_size = chunk_size;
         self
     }
 
+    /// Set the maximum size of a sized body which stays sized once tokens are inserted. Such
+    /// bodies are read and rewritten in memory, so the Content-Length of the response stays
+    /// exact: a response then needs about twice its size in additional memory, for the original
+    /// body and the rewritten one. Larger bodies are streamed. This have no effect if auto_insert
+    /// is set to false. Default value is the
+    /// [maximum chunk size](#method.set_auto_insert_max_chunk_size)
+    pub fn set_auto_insert_max_sized_body(mut self, size: u64) -> Self {
+        self.auto_insert_max_sized_body = Some(size);
+        self
+    }
+
+    /// Set the name of the cookie holding your application session. When set, tokens are bound to
+    /// the value of this cookie, so a token obtained for one session (for instance planted by a
+    /// related subdomain) can't be used with another one. See [`set_session_extractor`] if your
+    /// session identifier is not stored in a single cookie.
+    ///
+    /// With a [`set_token_store`], the cookie must hold an authenticated session id issued by your
+    /// server. Otherwise a client could fill the store with made-up sessions, evicting the tokens of
+    /// other users.
+    ///
+    /// [`set_session_extractor`]: #method.set_session_extractor
+    /// [`set_token_store`]: #method.set_token_store
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// # use rocket::Rocket;
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_session_cookie("session".to_owned())
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_session_cookie(self, cookie_name: String) -> Self {
+        self.set_session_extractor(move |request: &Request| {
+            request
+                .cookies()
+                .get(&cookie_name)
+                .map(|cookie| cookie.value().to_owned())
+        })
+    }
+
+    /// Set a function extracting the privilege level of a request, such as the role of the
+    /// logged-in user. Tokens are bound to it along with the session, so a new token value is
+    /// issued whenever it change, and tokens obtained before (for instance before logging in) are
+    /// rejected after. Returning `None` means the request has no particular privilege.
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::Request;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_session_cookie("session".to_owned())
+    ///                 .set_privilege_extractor(|request: &Request| {
+    ///                     request.cookies().get("role").map(|cookie| cookie.value().to_owned())
+    ///                 })
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_privilege_extractor<F>(mut self, extractor: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.privilege = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Set a function extracting the session identifier from a request. Tokens are then bound to
+    /// this identifier like with [`set_session_cookie`]. Returning `None` means the request is not
+    /// part of any session.
+    ///
+    /// [`set_session_cookie`]: #method.set_session_cookie
+    pub fn set_session_extractor<F>(mut self, extractor: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.session = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Set a server-side store for tokens, switching from the stateless double-submit cookie to
+    /// synchronizer tokens. Tokens are then checked against the one stored for the current
+    /// session, and can be revoked through the store. This require a session to be configured via
+    /// [`set_session_cookie`] or [`set_session_extractor`], otherwise [`finalize`] will fail.
+    /// Requests without a session get no token, and are rejected as if their token was invalid.
+    ///
+    /// The session extractor must return authenticated ids issued by your server: the store holds
+    /// one token per session, so a client able to choose its session ids could evict the tokens
+    /// of other users.
+    ///
+    /// [`set_session_cookie`]: #method.set_session_cookie
+    /// [`set_session_extractor`]: #method.set_session_extractor
+    /// [`finalize`]: #method.finalize
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::{CsrfFairingBuilder, MemoryTokenStore, TokenStore};
+    /// use std::sync::Arc;
+    /// # use rocket::Rocket;
+    ///
+    /// fn main() {
+    ///     let store = Arc::new(MemoryTokenStore::new());
+    ///     rocket::ignite()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_session_cookie("session".to_owned())
+    ///                 .set_token_store(store.clone())
+    ///                 .finalize().unwrap())
+    ///         .manage(store) //so your logout route can call store.revoke(session)
+    ///         //add your routes, other fairings...
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_token_store<S: TokenStore + 'static>(mut self, store: Arc<S>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Set the list of routes on which a token can be used only once, for sensitive actions like
+    /// password change or payment. Routes use the same syntax as exceptions, see
+    /// [`set_exceptions`], and match any method if none is given. These routes only accept tokens
+    /// issued less than the [`timeout`] ago, and used tokens are recorded until then in a
+    /// [`MemoryReplayCache`] holding up to 16k tokens, unless another cache is set with
+    /// [`set_replay_cache`].
+    ///
+    /// [`set_exceptions`]: #method.set_exceptions
+    /// [`set_replay_cache`]: #method.set_replay_cache
+    /// [`timeout`]: #method.set_timeout
+    /// [`MemoryReplayCache`]: struct.MemoryReplayCache.html
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// # use rocket::Rocket;
+    ///
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_single_use_routes(vec![
+    ///                     ("/account/password".to_owned(), Some(rocket::http::Method::Post)),
+    ///                     ("/payment/<id>".to_owned(), None),
+    ///                 ])
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_single_use_routes(mut self, routes: Vec<(String, Option<Method>)>) -> Self {
+        self.single_use = routes;
+        self
+    }
+
+    /// Set the cache used to detect replay of single-use tokens. See [`set_single_use_routes`].
+    ///
+    /// A cache has a limited capacity. Once full, it has to forget tokens before they expire, and
+    /// then rejects every token expiring before the last one it forgot, as it can't tell whether
+    /// it was already used. Such a token is handled like any CSRF violation, so pick a capacity
+    /// larger than the number of single-use submissions you expect during a token lifetime,
+    /// including the ones of a client submitting forms as fast as it can. The default is a
+    /// [`MemoryReplayCache`] holding 16k tokens.
+    ///
+    /// [`set_single_use_routes`]: #method.set_single_use_routes
+    /// [`MemoryReplayCache`]: struct.MemoryReplayCache.html
+    pub fn set_replay_cache<C: ReplayCache + 'static>(mut self, cache: Arc<C>) -> Self {
+        self.replay_cache = Some(cache);
+        self
+    }
+
+    /// Set if tokens should be bound to the form they are inserted in. If true, each token
+    /// automatically inserted is only valid for the action and method of its form, so a token
+    /// leaked from one form can't be used on another endpoint. Tokens inserted manually must then
+    /// be obtained with [`CsrfToken::for_form`]. Default is false.
+    ///
+    /// Tokens of forms overriding their method with a `_method` field are bound to the method of
+    /// the form itself. This field must come first in the form, as Rocket ignores it anywhere
+    /// else.
+    ///
+    /// [`CsrfToken::for_form`]: struct.CsrfToken.html#method.for_form
+    pub fn set_per_form_tokens(mut self, per_form: bool) -> Self {
+        self.per_form = per_form;
+        self
+    }
+
+    /// Set the origins of your application, such as `https://example.com`. Forms whose action is
+    /// an absolute url only get a token automatically inserted if it points to one of these
+    /// origins, so tokens are not leaked to payment providers or other sites. Forms with a relative
+    /// action always get a token. Default is empty.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_own_origins(vec![
+    ///                     "https://example.com".to_owned(),
+    ///                     "https://www.example.com".to_owned(),
+    ///                 ])
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_own_origins(mut self, origins: Vec<String>) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// Set the methods of forms getting a token automatically inserted. Default is POST only.
+    /// Browsers submit forms with other methods as GET, so only add methods like PUT or DELETE if
+    /// some javascript submit these forms with their declared method, otherwise tokens would end
+    /// up in urls.
+    pub fn set_form_methods(mut self, methods: Vec<Method>) -> Self {
+        self.forms.methods = methods
+            .iter()
+            .map(|method| method.as_str().as_bytes().to_vec())
+            .collect();
+        self
+    }
+
+    /// Set attributes giving both the action and the method of a form, such as the `hx-post` or
+    /// `hx-put` attributes of [htmx](https://htmx.org). Forms having one of these attributes get a
+    /// token whatever their `method` attribute is. Default is empty.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::http::Method;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// fn main() {
+    ///     let mut htmx = Vec::new();
+    ///     for (name, method) in &[("post", Method::Post), ("put", Method::Put), ("patch", Method::Patch)] {
+    ///         htmx.push((format!("hx-{}", name), *method));
+    ///         htmx.push((format!("data-hx-{}", name), *method));
+    ///     }
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_form_action_attributes(htmx)
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_form_action_attributes(mut self, attributes: Vec<(String, Method)>) -> Self {
+        self.forms.action_attributes = attributes
+            .iter()
+            .map(|(name, method)| {
+                (name.to_ascii_lowercase().into_bytes(), method.as_str().as_bytes().to_vec())
+            })
+            .collect();
+        self
+    }
+
+    /// Set if forms submitted with another method than their own by a button with a `formmethod`
+    /// attribute get a token. The token is then inserted before the first such button using one of
+    /// the [form methods](#method.set_form_methods). Note that it will be sent in the url if the
+    /// form is submitted by another mean. Default is false.
+    pub fn set_formmethod_buttons(mut self, formmethod: bool) -> Self {
+        self.forms.formmethod = formmethod;
+        self
+    }
+
+    /// Set the name of a request header in which scripts may send the token instead of in the
+    /// body, such as `X-XSRF-TOKEN` for Angular or axios. Such tokens are never bound to a form,
+    /// even with [per-form tokens](#method.set_per_form_tokens), as other sites can't set headers
+    /// on requests they forge.
+    pub fn set_request_token_header(mut self, header: String) -> Self {
+        self.request_header = Some(header);
+        self
+    }
+
+    /// Set the name of a response header carrying a fresh token on every response, including
+    /// non-html ones, for scripts to use on their next request.
+    pub fn set_response_token_header(mut self, header: String) -> Self {
+        self.response_header = Some(header);
+        self
+    }
+
+    /// Set the name of a cookie carrying a fresh token on every response, such as `XSRF-TOKEN`.
+    /// Unlike the cookie used to verify tokens, this one is readable by scripts, which must send
+    /// its value back in the [request header](#method.set_request_token_header).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    /// fn main() {
+    ///     rocket::ignite()
+    ///         .attach(CsrfFairingBuilder::new()
+    ///                 .set_token_cookie("XSRF-TOKEN".to_owned())
+    ///                 .set_request_token_header("X-XSRF-TOKEN".to_owned())
+    ///                 .finalize().unwrap())
+    ///         .launch();
+    /// }
+    /// ```
+    pub fn set_token_cookie(mut self, cookie_name: String) -> Self {
+        self.token_cookie = Some(cookie_name);
+        self
+    }
+
     /// Get the fairing from the builder.
+    #[allow(clippy::result_unit_err)]
     pub fn finalize(self) -> Result<CsrfFairing, ()> {
         let secret = self.secret.unwrap_or_else(|| {
             //use provided secret if one is
@@ -242,8 +776,15 @@ impl CsrfFairingBuilder {
         if default_target.map(&hashmap).is_none() {
             return Err(());
         } //verify if this path is valid as default path, i.e. it have at most one dynamic part which is <uri>
+        if self.store.is_some() && self.session.is_none() {
+            return Err(());
+        } //stored tokens are indexed by session
+        if self.auto_insert_meta && self.per_form {
+            return Err(());
+        } //a meta tag is not in any form
         Ok(CsrfFairing {
             duration: self.duration,
+            max_lifetime: self.max_lifetime,
             default_target: (default_target, self.default_target.1),
             exceptions: self
                 .exceptions
@@ -251,9 +792,39 @@ impl CsrfFairingBuilder {
                 .map(|(a, b, m)| (Path::from(a), Path::from(b), *m))//TODO verify if source and target are compatible
                 .collect(),
             secret,
+            algorithm: self.algorithm,
+            compact: self.compact,
             auto_insert: self.auto_insert,
             auto_insert_disable_prefix: self.auto_insert_disable_prefix,
+            auto_insert_exclusions: self
+                .auto_insert_exclusions
+                .iter()
+                .map(|(path, m)| (Path::from(path), *m))
+                .collect(),
             auto_insert_max_size: self.auto_insert_max_size,
+            auto_insert_max_sized_body: self.auto_insert_max_sized_body.unwrap_or(self.auto_insert_max_size),
+            session: self.session,
+            privilege: self.privilege,
+            store: self.store,
+            single_use: self
+                .single_use
+                .iter()
+                .map(|(path, m)| (Path::from(path), *m))
+                .collect(),
+            replay_cache: self
+                .replay_cache
+                .unwrap_or_else(|| Arc::new(MemoryReplayCache::default())),
+            per_form: self.per_form,
+            origins: self.origins,
+            forms: self.forms,
+            auto_insert_meta: self.auto_insert_meta,
+            request_header: self.request_header,
+            response_header: self.response_header,
+            token_cookie: self.token_cookie,
+            auto_insert_types: self.auto_insert_types,
+            element_handlers: self.element_handlers,
+            encoding_warned: AtomicBool::new(false),
+            charset_warned: AtomicBool::new(false),
         })
     }
 }
@@ -273,31 +844,136 @@ impl Default for CsrfFairingBuilder {
 /// [`CsrfFairingBuilder`]: /rocket_csrf/struct.CsrfFairing.html
 pub struct CsrfFairing {
     duration: u64,
+    max_lifetime: Option<u64>,
     default_target: (Path, Method),
     exceptions: Vec<(Path, Path, Option<Method>)>,
     secret: [u8; 32],
+    algorithm: Algorithm,
+    compact: bool,
     auto_insert: bool,
     auto_insert_disable_prefix: Vec<String>,
+    auto_insert_exclusions: Vec<(Path, Option<Method>)>,
     auto_insert_max_size: u64,
+    auto_insert_max_sized_body: u64,
+    session: Option<SessionExtractor>,
+    privilege: Option<SessionExtractor>,
+    store: Option<Arc<dyn TokenStore>>,
+    single_use: Vec<(Path, Option<Method>)>,
+    replay_cache: Arc<dyn ReplayCache>,
+    per_form: bool,
+    origins: Vec<String>,
+    forms: FormMatcher,
+    auto_insert_meta: bool,
+    request_header: Option<String>,
+    response_header: Option<String>,
+    token_cookie: Option<String>,
+    auto_insert_types: Vec<ContentType>,
+    element_handlers: Vec<HandlerFactory>,
+    encoding_warned: AtomicBool,
+    charset_warned: AtomicBool,
 }
 
-impl Fairing for CsrfFairing {
-    fn info(&self) -> Info {
-        if self.auto_insert {
-            Info {
-                name: "CSRF protection",
-                kind: Kind::Attach | Kind::Request | Kind::Response,
-            }
+impl CsrfFairing {
+    fn inserter(&self, token: CsrfToken, page: &str) -> CsrfInserter<'static> {
+        let inserter = if self.per_form {
+            let page = page.to_owned();
+            CsrfInserter::with_generator(move |method, action| {
+                let action = resolve_uri(&page, &String::from_utf8_lossy(action));
+                token.bound_value(method, &action).into_bytes()
+            })
+        } else if self.auto_insert_meta {
+            CsrfInserter::new(token.value()).with_meta(token.value())
         } else {
-            Info {
-                name: "CSRF protection",
-                kind: Kind::Attach | Kind::Request,
-            }
-        }
+            CsrfInserter::new(token.value())
+        };
+        inserter
+            .with_origins(self.origins.clone())
+            .with_forms(self.forms.clone())
+    }
+
+    // decode a body, rewrite its elements, and encode it back
+    fn rewrite<'a, H: ElementHandler + 'a>(
+        &self,
+        body: Box<dyn Read + 'a>,
+        handler: H,
+        encoding: Encoding,
+        charset: Charset,
+        xml: bool,
+    ) -> Box<dyn Read + 'a> {
+        let body = charset.transcode(encoding.decode(body), |body| {
+            Box::new(
+                HtmlRewriter::new(body, handler)
+                    .with_xml_syntax(xml)
+                    .with_max_buffer(self.auto_insert_max_size as usize),
+            )
+        });
+        encoding.encode(body)
+    }
+}
+
+// check if a request match one of a list of routes, with an optional method
+fn matches_route(routes: &[(Path, Option<Method>)], request: &Request) -> bool {
+    let uri = request.uri().to_string();
+    routes.iter().any(|(path, method)| {
+        method.map_or(true, |m| m == request.method()) && path.extract(&uri).is_some()
+    })
+}
+
+type SessionExtractor = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+type HandlerFactory = Arc<dyn Fn(&Request) -> Box<dyn ElementHandler> + Send + Sync>;
+
+/// State shared with request guards through Rocket's managed state.
+pub struct CsrfState {
+    pub engine: CsrfProtection,
+    pub duration: u64,
+    pub store: Option<Arc<dyn TokenStore>>,
+    pub token_cookie: Option<String>,
+    session: Option<SessionExtractor>,
+    privilege: Option<SessionExtractor>,
+}
+
+impl CsrfState {
+    /// Get the session identifier tokens must be bound to, empty if there is none.
+    /// This borrow request cookies, so it must not be called while they are already borrowed.
+    pub fn session_id(&self, request: &Request) -> Vec<u8> {
+        self.session
+            .as_ref()
+            .and_then(|extractor| extractor(request))
+            .map(String::into_bytes)
+            .unwrap_or_default()
+    }
+
+    /// Get what tokens are bound to: the session identifier, and the privilege level of the
+    /// request if there is one. This also borrow request cookies.
+    pub fn binding(&self, request: &Request, session: &[u8]) -> Vec<u8> {
+        match self.privilege.as_ref().and_then(|extractor| extractor(request)) {
+            Some(privilege) => bind_privilege(session, privilege.as_bytes()),
+            None => session.to_vec(),
+        }
+    }
+}
+
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF protection",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        } //responses are always seen, at least to remove the opt-out header
     }
 
     fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
-        Ok(rocket.manage((CsrfProtection::from_key(self.secret), self.duration))) //add the Csrf engine to Rocket's managed state
+        Ok(rocket.manage(CsrfState {
+            engine: CsrfProtection::from_key(self.secret)
+                .with_algorithm(self.algorithm)
+                .with_compact(self.compact)
+                .with_max_lifetime(self.max_lifetime),
+            duration: self.duration,
+            store: self.store.clone(),
+            token_cookie: self.token_cookie.clone(),
+            session: self.session.clone(),
+            privilege: self.privilege.clone(),
+        })) //add the Csrf engine to Rocket's managed state
     }
 
     fn on_request(&self, request: &mut Request, data: &Data) {
@@ -315,18 +991,27 @@ impl Fairing for CsrfFairing {
             }
         }
 
-        let (csrf_engine, _) = request
-            .guard::<State<(CsrfProtection, u64)>>()
-            .unwrap()
-            .inner();
+        let state = request.guard::<State<CsrfState>>().unwrap().inner();
+        let csrf_engine = &state.engine;
+        let session = state.session_id(request);
+        let binding = state.binding(request, &session);
 
+        let stored = state
+            .store
+            .as_ref()
+            .filter(|_| !session.is_empty()) //all clients without a session would share one token
+            .and_then(|store| store.get(&session));
         let mut cookie = request
             .cookies()
             .get(CSRF_COOKIE_NAME)
             .and_then(|cookie| BASE64URL_NOPAD.decode(cookie.value().as_bytes()).ok());
-        let cookie = cookie.as_mut().and_then(|c| csrf_engine.parse_cookie(&mut *c).ok()); //get and parse Csrf cookie
+        let cookie = if state.store.is_some() {
+            stored.as_ref().and_then(|(tag, expires)| CsrfCookie::from_stored(tag, *expires, &binding))
+        } else {
+            cookie.as_mut().and_then(|c| csrf_engine.parse_cookie(&mut *c, &binding).ok())
+        }; //get and parse Csrf cookie, or the token stored server-side
 
-        let mut token = if request
+        let token = if request
             .content_type()
             .map(|c| c.media_type())
             .filter(|m| m.top() == "multipart" && m.sub() == "form-data")
@@ -349,12 +1034,44 @@ impl Fairing for CsrfFairing {
                 })
                 .next()
         }.and_then(|token| BASE64URL_NOPAD.decode(token).ok());
-        let token = token.as_mut().and_then(|token| csrf_engine.parse_token(&mut *token).ok());
+        let from_header = token.is_none();
+        let mut token = token.or_else(|| {
+            self.request_header
+                .as_ref()
+                .and_then(|name| request.headers().get_one(name))
+                .and_then(|token| BASE64URL_NOPAD.decode(token.trim().as_bytes()).ok())
+        }); //scripts may send the token in a header instead of the body
+        let aad = if self.per_form && !from_header {
+            let peek = data.peek();
+            let overridable = &peek[..peek.len().min("_method=delete".len())]; //all Rocket looks at
+            let method = if request.method() != Post
+                && request.content_type().map_or(false, |ct| ct.is_form())
+                && parse_args(from_utf8(overridable).unwrap_or(""))
+                    .find(|&(key, _)| key == "_method")
+                    .map_or(false, |(_, method)| method.parse() == Ok(request.method()))
+            {
+                Post //Rocket already applied the method override of this form
+            } else {
+                request.method()
+            };
+            form_aad(&binding, method.as_str().as_bytes(), &normalize_uri(&request.uri().to_string()))
+        } else {
+            binding
+        };
+        let token = token.as_mut().and_then(|token| csrf_engine.parse_token(&mut *token, &aad).ok());
 
         if let Some(token) = token {
             if let Some(cookie) = cookie {
                 if csrf_engine.verify_token_pair(&token, &cookie) {
-                    return; //if we got both token and cookie, and they match each other, we do nothing
+                    if !matches_route(&self.single_use, request) {
+                        return; //if we got both token and cookie, and they match each other, we do nothing
+                    }
+                    //single-use tokens are only accepted for the timeout after they were issued,
+                    //and recorded until then, as the cookie may be renewed well past that
+                    let expires = token.issued().saturating_add(state.duration);
+                    if now().map_or(false, |now| expires > now) && self.replay_cache.insert(token.id(), expires) {
+                        return;
+                    }
                 }
             }
         }
@@ -389,25 +1106,61 @@ impl Fairing for CsrfFairing {
     }
 
     fn on_response(&self, request: &Request, response: &mut Response) {
-        if let Some(ct) = response.content_type() {
-            if !ct.is_html() {
-                return;
-            }
-        } //if content type is not html, we do nothing
-
+        let content_type = response.content_type();
+        let is_html = content_type
+            .as_ref()
+            .map_or(true, |ct| self.auto_insert_types.contains(ct));
+        let xml = content_type
+            .as_ref()
+            .map_or(false, |ct| ct.sub() == "xml" || ct.sub().as_str().to_ascii_lowercase().ends_with("+xml"));
+        let opt_out = response.headers().contains(NO_INSERT_HEADER);
+        response.remove_header(NO_INSERT_HEADER); //private to the application
         let uri = request.uri().to_string();
-        if self
-            .auto_insert_disable_prefix
-            .iter()
-            .any(|prefix| uri.starts_with(prefix))
-        {
+        let rewritable = is_html
+            && !opt_out
+            && !self
+                .auto_insert_disable_prefix
+                .iter()
+                .any(|prefix| uri.starts_with(prefix))
+            && !matches_route(&self.auto_insert_exclusions, request);
+        let mut insert = self.auto_insert && rewritable;
+        let mut handle = !self.element_handlers.is_empty() && rewritable;
+        let encoding = Encoding::from_header(response.headers().get_one("Content-Encoding"));
+        if (insert || handle) && encoding.is_none() {
+            if !self.encoding_warned.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "[rocket_csrf] Can't insert tokens into html encoded with {}, you should enable the \"compression\" feature or attach this fairing before compressing responses",
+                    response.headers().get_one("Content-Encoding").unwrap_or_default()
+                );
+            }
+            insert = false;
+            handle = false;
+        } //don't scan compressed bytes, we would find no form in them
+        let charset = response.content_type().and_then(|ct| {
+            ct.params()
+                .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+                .map(|(_, value)| value.to_owned())
+        });
+        let charset = Charset::from_name(charset.as_ref().map(|charset| &charset[..]));
+        if (insert || handle) && charset.is_none() {
+            if !self.charset_warned.swap(true, Ordering::Relaxed) {
+                warn!("[rocket_csrf] Can't insert tokens into html in an unsupported charset, you should serve it as utf-8");
+            }
+            insert = false;
+            handle = false;
+        } //markup can't be found byte by byte in these charsets
+        if !insert && !handle && self.response_header.is_none() && self.token_cookie.is_none() {
             return;
-        } //if request is on an ignored prefix, ignore it
+        } /* if content type is not html, or request is on an ignored prefix, we do nothing,
+           * unless the token is also sent outside of the body
+           */
 
         let token = match request.guard::<CsrfToken>() {
             Outcome::Success(t) => {
-                response.adjoin_header(request.cookies().get(CSRF_COOKIE_NAME).unwrap());
-                t
+                if let Some(cookie) = request.cookies().get(CSRF_COOKIE_NAME) {
+                    response.adjoin_header(cookie);
+                } //there is no cookie when tokens are stored server-side
+                Some(t)
             } //guard can't add/remove cookies in on_response, add headers manually
             Outcome::Forward(_) => {
                 if request.cookies().get(CSRF_COOKIE_NAME).is_some() {
@@ -417,36 +1170,77 @@ impl Fairing for CsrfFairing {
                             .finish(),
                     );
                 }
-                return;
+                None
             } //guard can't add/remove cookies in on_response, add headers manually
             Outcome::Failure(_) => return,
         }; /* if we can't get a token, leave request unchanged, this probably
             * means the request had no cookies from the begining
             */
 
+        if let Some(ref token) = token {
+            let value = String::from_utf8_lossy(token.value()).into_owned();
+            if let Some(ref name) = self.response_header {
+                response.set_raw_header(name.clone(), value.clone());
+            }
+            if let Some(ref name) = self.token_cookie {
+                response.adjoin_header(
+                    Cookie::build(name.clone(), value)
+                        .secure(true)
+                        .same_site(SameSite::Strict)
+                        .path("/")
+                        .max_age(Duration::seconds(self.duration as i64))
+                        .finish(),
+                ); //readable by scripts, which must send it back in the request header
+            }
+        }
+        let inserter = match token {
+            Some(token) if insert => Some(self.inserter(token, &uri)),
+            _ => None,
+        };
+        let handlers: Vec<Box<dyn ElementHandler>> = if handle {
+            self.element_handlers.iter().map(|factory| factory(request)).collect()
+        } else {
+            Vec::new()
+        }; //other handlers also rewrite pages without a token
+        if inserter.is_none() && handlers.is_empty() {
+            return;
+        }
+        let handler = (inserter, handlers);
+
         let body = response.take_body(); //take request body from Rocket
         if body.is_none() {
             return;
         } //if there was no body, leave it that way
         let body = body.unwrap();
 
-        if let Sized(body_reader, len) = body {
-            if len <= self.auto_insert_max_size {
-                //if this is a small enought body, process the full body
+        let encoding = encoding.unwrap_or(Encoding::Identity);
+        let charset = charset.unwrap_or(Charset::AsciiCompatible);
+        if let Sized(mut body_reader, len) = body {
+            if len <= self.auto_insert_max_sized_body {
+                //if body is of known and reasonable size, rewrite it at once so it stays sized
+                let mut raw = Vec::with_capacity(len as usize);
+                if body_reader.read_to_end(&mut raw).is_err() {
+                    response.set_streamed_body(Cursor::new(raw).chain(body_reader));
+                    return;
+                } //send what was read and the rest unchanged, so Rocket get the error again
                 let mut res = Vec::with_capacity(len as usize);
-                CsrfProxy::from(body_reader, token.value())
-                    .read_to_end(&mut res)
-                    .unwrap();
-                response.set_sized_body(Cursor::new(res));
+                let rewritten = self
+                    .rewrite(Box::new(&raw[..]), handler, encoding, charset, xml)
+                    .read_to_end(&mut res);
+                if rewritten.is_ok() {
+                    response.set_sized_body(Cursor::new(res));
+                } else {
+                    response.set_sized_body(Cursor::new(raw));
+                } //if body can't be decoded, send it unchanged
             } else {
                 //if body is of known but long size, change it to a stream to preserve memory, by encapsulating it into our "proxy" struct
-                let body = body_reader;
-                response.set_streamed_body(Box::new(CsrfProxy::from(body, token.value())));
+                let body = self.rewrite(body_reader, handler, encoding, charset, xml);
+                response.set_streamed_body(body);
             }
         } else {
             //if body is of unknown size, encapsulate it into our "proxy" struct
-            let body = body.into_inner();
-            response.set_streamed_body(Box::new(CsrfProxy::from(body, token.value())));
+            let body = self.rewrite(body.into_inner(), handler, encoding, charset, xml);
+            response.set_streamed_body(body);
         }
     }
 }
@@ -461,6 +1255,8 @@ mod tests {
         Rocket,
     };
 
+    const ALGORITHMS: &[Algorithm] = &[Algorithm::ChaCha20Poly1305, Algorithm::Aes256Gcm, Algorithm::HmacSha256];
+
     fn default_builder() -> CsrfFairingBuilder {
         super::CsrfFairingBuilder::new()
             .set_default_target("/csrf".to_owned(), Method::Get)
@@ -490,18 +1286,541 @@ mod tests {
                     target_ex1,
                     post_ex2,
                     target_ex2,
-                    static_route
+                    static_route,
+                    forms,
+                    encoded_forms,
+                    broken_body,
+                    external_forms,
+                    htmx_forms,
+                    page,
+                    encoded,
+                    charset,
+                    multibyte,
+                    xhtml,
+                    no_insert,
+                    no_insert_wrapper,
+                    long_tag
                 ],
             )
             .attach(csrf_fairing)
     }
 
-    fn get_token(client: &Client) -> (String, String) {
-        let mut response = client
-            .get("/token")
-            .cookie(Cookie::new("some", "cookie"))
-            .dispatch(); //get token and cookie
-        let token = response.body_string().unwrap();
+    fn get_token(client: &Client) -> (String, String) {
+        let mut response = client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //get token and cookie
+        let token = response.body_string().unwrap();
+        let cookie = response
+            .headers()
+            .get("set-cookie")
+            .next()
+            .unwrap()
+            .split(|c| c == '=' || c == ';')
+            .nth(1)
+            .unwrap()
+            .to_owned();
+        (token, cookie)
+    }
+
+    fn post_token(client: &Client, path: String, token: String, cookie: String) -> LocalRequest {
+        let token = if !token.is_empty() {
+            let mut t = Vec::new();
+            t.append(&mut CSRF_FORM_FIELD.as_bytes().to_vec());
+            t.push(0x3D); //'='
+            t.append(&mut token.as_bytes().to_vec());
+            t
+        } else {
+            Vec::new()
+        };
+        let req = client.post(path).body(&token);
+        if !cookie.is_empty() {
+            req.cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+        } else {
+            req
+        }
+    }
+
+    #[test]
+    fn test_redirection_on_failure() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //violation well detected
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        let mut response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection on post
+        assert_eq!(response.body_string(), Some("target-ex1".to_owned()));
+
+        let mut response = client.post("/ex2/abcd").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection with dyn part
+        assert_eq!(response.body_string(), Some("abcd".to_owned()));
+    }
+
+    #[test]
+    fn test_non_redirection() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //no redirection on get
+        assert_eq!(response.body_string(), Some("get-ex1".to_owned()));
+
+        let (token, cookie) = get_token(&client);
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        let mut response =
+            post_token(&client, "/ex1".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("post-ex1".to_owned()));
+
+        let mut response = post_token(
+            &client,
+            "/ex2/some-url".to_owned(),
+            token.clone(),
+            cookie.clone(),
+        ).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("valid-dyn-req".to_owned()));
+    }
+
+    #[test]
+    fn test_token_timeout() {
+        let rocket = default_rocket(default_builder().set_timeout(5).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+        ::std::thread::sleep(::std::time::Duration::from_secs(6));
+
+        //access / with timed out token
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let rocket = default_rocket(default_builder().set_timeout(60).set_max_lifetime(3).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let refresh = |cookie: &str| {
+            let mut response = client
+                .get("/token")
+                .cookie(Cookie::new("some", "cookie"))
+                .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.to_owned()))
+                .dispatch();
+            let set_cookie = response.headers().get_one("set-cookie").unwrap().to_owned();
+            let cookie = set_cookie.split(|c| c == '=' || c == ';').nth(1).unwrap().to_owned();
+            (response.body_string().unwrap(), cookie, set_cookie)
+        };
+        let post = |token: &str, cookie: &str| {
+            post_token(&client, "/".to_owned(), token.to_owned(), cookie.to_owned())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch()
+                .body_string()
+                .unwrap()
+        };
+
+        let (token, cookie) = get_token(&client);
+        ::std::thread::sleep(::std::time::Duration::from_secs(1));
+        let (_, refreshed, set_cookie) = refresh(&cookie);
+        assert!(set_cookie.contains("Max-Age=1") || set_cookie.contains("Max-Age=2")); //capped by lifetime
+        assert_eq!(post(&token, &refreshed), "success"); //value kept while it's active
+
+        ::std::thread::sleep(::std::time::Duration::from_secs(3));
+        assert_eq!(post(&token, &refreshed), "violation"); //even active, value outlived its lifetime
+        let (rotated, rotated_cookie, _) = refresh(&refreshed);
+        assert_eq!(post(&rotated, &rotated_cookie), "success");
+        assert_eq!(post(&token, &rotated_cookie), "violation");
+    }
+
+    #[test]
+    fn test_privilege_rotation() {
+        use token_store::MemoryTokenStore;
+
+        let role = |request: &Request| request.cookies().get("role").map(|c| c.value().to_owned());
+        let rocket = default_rocket(
+            default_builder()
+                .set_session_cookie("some".to_owned())
+                .set_privilege_extractor(role)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let get = |role: &str, cookie: Option<&str>| {
+            let mut request = client
+                .get("/token")
+                .cookie(Cookie::new("some", "cookie"))
+                .cookie(Cookie::new("role", role.to_owned()));
+            if let Some(cookie) = cookie {
+                request = request.cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.to_owned()));
+            }
+            let mut response = request.dispatch();
+            let cookie = response
+                .headers()
+                .get_one("set-cookie")
+                .map(|c| c.split(|c| c == '=' || c == ';').nth(1).unwrap().to_owned());
+            (response.body_string().unwrap(), cookie.unwrap_or_default())
+        };
+        let post = |client: &Client, role: &str, token: &str, cookie: &str| {
+            post_token(client, "/".to_owned(), token.to_owned(), cookie.to_owned())
+                .cookie(Cookie::new("some", "cookie"))
+                .cookie(Cookie::new("role", role.to_owned()))
+                .dispatch()
+                .body_string()
+                .unwrap()
+        };
+
+        let (token, cookie) = get("user", None);
+        assert_eq!(post(&client, "user", &token, &cookie), "success");
+        assert_eq!(post(&client, "admin", &token, &cookie), "violation");
+
+        //the cookie of the previous privilege level is replaced by a new value
+        let (admin_token, admin_cookie) = get("admin", Some(&cookie));
+        assert_eq!(post(&client, "admin", &admin_token, &admin_cookie), "success");
+        assert_eq!(post(&client, "admin", &token, &admin_cookie), "violation");
+
+        //stored tokens are rotated as well
+        let store = Arc::new(MemoryTokenStore::new());
+        let rocket = default_rocket(
+            default_builder()
+                .set_session_cookie("some".to_owned())
+                .set_privilege_extractor(role)
+                .set_token_store(store.clone())
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let mut response = client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .cookie(Cookie::new("role", "user"))
+            .dispatch();
+        let token = response.body_string().unwrap();
+        let stored = store.get(b"cookie").unwrap();
+        assert_eq!(post(&client, "user", &token, ""), "success");
+        assert_eq!(post(&client, "admin", &token, ""), "violation");
+        client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .cookie(Cookie::new("role", "admin"))
+            .dispatch();
+        assert_ne!(store.get(b"cookie").unwrap().0, stored.0);
+        assert_eq!(post(&client, "user", &token, ""), "violation");
+    }
+
+    #[test]
+    fn test_invalid_token_pair() {
+        let rocket1 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
+        let client1 = Client::new(rocket1).expect("valid rocket instance");
+        let rocket2 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
+        let client2 = Client::new(rocket2).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client1);
+
+        //having only one part fail
+        let mut response =
+            post_token(&client2, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        let mut response =
+            post_token(&client1, "/".to_owned(), "".to_owned(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        let (token2, _cookie2) = get_token(&client2);
+
+        //having 2 incompatible parts fail
+        let mut response =
+            post_token(&client1, "/".to_owned(), token2.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_algorithm() {
+        let clients: Vec<_> = ALGORITHMS
+            .iter()
+            .map(|&algorithm| {
+                let builder = default_builder()
+                    .set_secret([0; 32])
+                    .set_algorithm(algorithm)
+                    .set_session_cookie("some".to_owned());
+                Client::new(default_rocket(builder.finalize().unwrap())).expect("valid rocket instance")
+            })
+            .collect();
+
+        for (i, client) in clients.iter().enumerate() {
+            //each algorithm masks tokens and binds them to the session
+            let (token, cookie) = get_token(client);
+            let mut response = client
+                .get("/token")
+                .cookie(Cookie::new("some", "cookie"))
+                .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+                .dispatch();
+            let token2 = response.body_string().unwrap();
+            assert_ne!(token, token2);
+            let mut response =
+                post_token(client, "/".to_owned(), token2, cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+            assert_eq!(response.body_string(), Some("success".to_owned()));
+            let mut response =
+                post_token(client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "other")).dispatch();
+            assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+            //tokens are rejected by other algorithms, even with the same secret
+            for (j, other) in clients.iter().enumerate() {
+                let mut response = post_token(other, "/".to_owned(), token.clone(), cookie.clone())
+                    .cookie(Cookie::new("some", "cookie"))
+                    .dispatch();
+                let expected = if i == j { "success" } else { "violation" };
+                assert_eq!(response.body_string(), Some(expected.to_owned()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_tokens() {
+        let rocket = default_rocket(default_builder().set_secret([0; 32]).set_compact_tokens(true).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let rocket = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
+        let standard = Client::new(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        assert_eq!(token.len(), 71);
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        //the format is recorded, compact tokens are rejected by the default format
+        let mut response =
+            post_token(&standard, "/".to_owned(), token, cookie).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_protection_interop() {
+        let rocket = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let protection = ::protection::CsrfProtection::from_key([0; 32]);
+
+        //tokens issued by the fairing are verified outside of it
+        let (token, cookie) = get_token(&client);
+        assert!(protection.verify_encoded(&token, &cookie, b"").is_ok());
+
+        //and tokens issued outside of it are accepted by the fairing
+        let pair = protection.generate(b"", 60).unwrap();
+        let mut response = post_token(&client, "/".to_owned(), pair.token().to_owned(), pair.cookie().to_owned())
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+    }
+
+    #[test]
+    fn test_session_binding() {
+        let rocket = default_rocket(default_builder().set_session_cookie("some".to_owned()).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client); //bound to session "cookie"
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        //same pair used from another session fail
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "other")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("unrelated", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_token_store() {
+        use token_store::MemoryTokenStore;
+
+        let store = Arc::new(MemoryTokenStore::new());
+        assert!(default_builder().set_token_store(store.clone()).finalize().is_err()); //session is mandatory
+
+        let rocket = default_rocket(
+            default_builder()
+                .set_session_cookie("some".to_owned())
+                .set_token_store(store.clone())
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/token").cookie(Cookie::new("some", "cookie")).dispatch();
+        let token = response.body_string().unwrap();
+        assert!(response.headers().get("set-cookie").next().is_none()); //no cookie in this mode
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "other")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        store.revoke(b"cookie");
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_token_store_without_session() {
+        use token_store::MemoryTokenStore;
+
+        let store = Arc::new(MemoryTokenStore::new());
+        let rocket = default_rocket(
+            default_builder()
+                .set_session_cookie("session".to_owned())
+                .set_token_store(store.clone())
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        //clients without a session get no token, as they would all share the same one
+        let response = client.get("/token").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.status(), ::rocket::http::Status::NotFound);
+        assert!(store.get(b"").is_none());
+
+        let mut response = client.get("/token").cookie(Cookie::new("session", "cookie")).dispatch();
+        let token = response.body_string().unwrap();
+        let mut response =
+            post_token(&client, "/".to_owned(), token, "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_single_use() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_single_use_routes(vec![("/".to_owned(), Some(Method::Post))])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        //replayed token fail
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        //but can still be used on other routes
+        let mut response =
+            post_token(&client, "/ex1".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("post-ex1".to_owned()));
+
+        let (token, cookie) = get_token(&client);
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+    }
+
+    #[test]
+    fn test_single_use_after_renewal() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_timeout(2)
+                .set_single_use_routes(vec![("/".to_owned(), Some(Method::Post))])
+                .set_replay_cache(Arc::new(MemoryReplayCache::new(1)))
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let refresh = |cookie: &str| {
+            let mut response = client
+                .get("/token")
+                .cookie(Cookie::new("some", "cookie"))
+                .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.to_owned()))
+                .dispatch();
+            let set_cookie = response.headers().get_one("set-cookie").unwrap().to_owned();
+            let cookie = set_cookie.split(|c| c == '=' || c == ';').nth(1).unwrap().to_owned();
+            (response.body_string().unwrap(), cookie)
+        };
+        let post = |token: &str, cookie: &str| {
+            post_token(&client, "/".to_owned(), token.to_owned(), cookie.to_owned())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch()
+                .body_string()
+                .unwrap()
+        };
+
+        let (token, cookie) = get_token(&client);
+        assert_eq!(post(&token, &cookie), "success");
+        ::std::thread::sleep(::std::time::Duration::from_secs(1));
+        let (second, cookie) = refresh(&cookie);
+        assert_eq!(post(&second, &cookie), "success"); //evict the first token from the cache
+        assert_eq!(post(&token, &cookie), "violation");
+
+        //the first cookie expired, but the value is kept alive by renewals
+        ::std::thread::sleep(::std::time::Duration::from_millis(1500));
+        let (fresh, cookie) = refresh(&cookie);
+        assert_eq!(post(&token, &cookie), "violation");
+        assert_eq!(post(&fresh, &cookie), "success");
+    }
+
+    #[test]
+    fn test_per_form_tokens() {
+        let rocket = default_rocket(default_builder().set_per_form_tokens(true).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/forms").cookie(Cookie::new("some", "cookie")).dispatch();
+        let body = response.body_string().unwrap();
+        let cookie = response
+            .headers()
+            .get("set-cookie")
+            .next()
+            .unwrap()
+            .split(|c| c == '=' || c == ';')
+            .nth(1)
+            .unwrap()
+            .to_owned();
+        let tokens: Vec<_> = body
+            .split("value=\"")
+            .skip(1)
+            .map(|part| part.split('"').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(tokens.len(), 2);
+
+        let mut response =
+            post_token(&client, "/".to_owned(), tokens[0].clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("success".to_owned()));
+
+        let mut response =
+            post_token(&client, "/ex2/some-url".to_owned(), tokens[1].clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("valid-dyn-req".to_owned()));
+
+        //tokens can't be used on another form's endpoint
+        let mut response =
+            post_token(&client, "/".to_owned(), tokens[1].clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+
+        //neither can unbound tokens
+        let (token, cookie) = get_token(&client);
+        let mut response =
+            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_per_form_encoded_actions() {
+        let rocket = default_rocket(default_builder().set_per_form_tokens(true).finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/encoded-forms").cookie(Cookie::new("some", "cookie")).dispatch();
+        let body = response.body_string().unwrap();
         let cookie = response
             .headers()
             .get("set-cookie")
@@ -511,111 +1830,283 @@ mod tests {
             .nth(1)
             .unwrap()
             .to_owned();
-        (token, cookie)
+        let tokens: Vec<_> = body
+            .split("value=\"")
+            .skip(1)
+            .map(|part| part.split('"').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(tokens.len(), 2);
+
+        //actions are checked as browsers send them, whichever way they are encoded
+        for &(path, token) in &[
+            ("/ex2/my%20page?a=1&b=%3C2%3E%27", &tokens[0]),
+            ("/ex2/my%20page?a=1&b=%3c2%3e'", &tokens[0]),
+            ("/ex2/caf%C3%A9", &tokens[1]),
+            ("/ex2/caf%c3%a9", &tokens[1]),
+        ] {
+            let mut response = post_token(&client, path.to_owned(), token.clone(), cookie.clone())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch();
+            assert_eq!(response.body_string(), Some("valid-dyn-req".to_owned()), "{}", path);
+        }
+        let mut response = post_token(&client, "/ex2/caf%C3%A9".to_owned(), tokens[0].clone(), cookie.clone())
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.body_string(), Some("café".to_owned())); //rerouted by the exception
     }
 
-    fn post_token(client: &Client, path: String, token: String, cookie: String) -> LocalRequest {
-        let token = if token.len() > 0 {
-            let mut t = Vec::new();
-            t.append(&mut CSRF_FORM_FIELD.as_bytes().to_vec());
-            t.push(0x3D); //'='
-            t.append(&mut token.as_bytes().to_vec());
-            t
-        } else {
-            Vec::new()
-        };
-        let req = client.post(path).body(&token);
-        if !cookie.is_empty() {
-            req.cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
-        } else {
-            req
+    #[test]
+    fn test_token_masking() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let mut response = client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+            .dispatch();
+        let token2 = response.body_string().unwrap();
+        assert_ne!(token, token2); //same cookie, but each response get a differently masked token
+
+        for token in &[token, token2] {
+            let mut response =
+                post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+            assert_eq!(response.body_string(), Some("success".to_owned()));
         }
     }
 
     #[test]
-    fn test_redirection_on_failure() {
-        let rocket = default_rocket(default_builder().finalize().unwrap());
+    fn test_own_origins() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_own_origins(vec!["https://example.com".to_owned()])
+                .finalize()
+                .unwrap(),
+        );
         let client = Client::new(rocket).expect("valid rocket instance");
 
-        let mut response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //violation well detected
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        let mut response = client
+            .get("/external-forms")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        let body = response.body_string().unwrap();
+        assert_eq!(body.matches("csrf-token").count(), 1); //only the own origin form get a token
+        assert!(body.starts_with("<div><form method='POST' action='https://payment.example.org/'></form>"));
+    }
 
-        let mut response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection on post
-        assert_eq!(response.body_string(), Some("target-ex1".to_owned()));
+    #[test]
+    fn test_form_recognition() {
+        let client = |builder: CsrfFairingBuilder| {
+            Client::new(default_rocket(builder.finalize().unwrap())).expect("valid rocket instance")
+        };
+        let count_tokens = |client: Client| {
+            let mut response = client
+                .get("/htmx-forms")
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch();
+            response.body_string().unwrap().matches("csrf-token").count()
+        };
 
-        let mut response = client.post("/ex2/abcd").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection with dyn part
-        assert_eq!(response.body_string(), Some("abcd".to_owned()));
+        assert_eq!(count_tokens(client(default_builder())), 0);
+        assert_eq!(
+            count_tokens(client(
+                default_builder()
+                    .set_form_action_attributes(vec![("HX-Put".to_owned(), Method::Put)])
+                    .set_formmethod_buttons(true)
+            )),
+            2
+        );
     }
 
     #[test]
-    fn test_non_redirection() {
-        let rocket = default_rocket(default_builder().finalize().unwrap());
+    fn test_meta_tag() {
+        let rocket = default_rocket(default_builder().set_auto_insert_meta(true).finalize().unwrap());
         let client = Client::new(rocket).expect("valid rocket instance");
 
-        let mut response = client.get("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //no redirection on get
-        assert_eq!(response.body_string(), Some("get-ex1".to_owned()));
-
-        let (token, cookie) = get_token(&client);
+        let mut response = client.get("/page").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookie = response
+            .headers()
+            .get("set-cookie")
+            .next()
+            .unwrap()
+            .split(|c| c == '=' || c == ';')
+            .nth(1)
+            .unwrap()
+            .to_owned();
+        let body = response.body_string().unwrap();
+        let token = body
+            .split("<meta name=\"csrf-token\" content=\"")
+            .nth(1)
+            .and_then(|part| part.split('"').next())
+            .unwrap()
+            .to_owned();
 
-        let mut response =
-            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        let mut response = post_token(&client, "/".to_owned(), token, cookie).cookie(Cookie::new("some", "cookie")).dispatch();
         assert_eq!(response.body_string(), Some("success".to_owned()));
 
-        let mut response =
-            post_token(&client, "/ex1".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("post-ex1".to_owned()));
+        assert!(default_builder()
+            .set_auto_insert_meta(true)
+            .set_per_form_tokens(true)
+            .finalize()
+            .is_err());
+    }
 
-        let mut response = post_token(
-            &client,
-            "/ex2/some-url".to_owned(),
-            token.clone(),
-            cookie.clone(),
-        ).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("valid-dyn-req".to_owned()));
+    #[test]
+    fn test_token_header() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_per_form_tokens(true)
+                .set_token_cookie("XSRF-TOKEN".to_owned())
+                .set_request_token_header("X-XSRF-TOKEN".to_owned())
+                .set_response_token_header("X-CSRF-Token".to_owned())
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let response = client.get("/csrf").cookie(Cookie::new("some", "cookie")).dispatch(); //not html
+        let cookie_value = |name: &str| {
+            response
+                .headers()
+                .get("set-cookie")
+                .find(|c| c.starts_with(&format!("{}=", name)))
+                .map(|c| c.split(|c| c == '=' || c == ';').nth(1).unwrap().to_owned())
+                .unwrap()
+        };
+        let (cookie, readable) = (cookie_value(CSRF_COOKIE_NAME), cookie_value("XSRF-TOKEN"));
+        let header = response.headers().get_one("X-CSRF-Token").unwrap().to_owned();
+        assert!(!response.headers().get("set-cookie").any(|c| c.starts_with("XSRF-TOKEN=") && c.contains("HttpOnly")));
+
+        for token in &[readable, header] {
+            let mut response = post_token(&client, "/".to_owned(), String::new(), cookie.clone())
+                .cookie(Cookie::new("some", "cookie"))
+                .header(Header::new("X-XSRF-TOKEN", token.clone()))
+                .dispatch();
+            assert_eq!(response.body_string(), Some("success".to_owned()));
+        }
+
+        let mut response = post_token(&client, "/".to_owned(), String::new(), cookie)
+            .cookie(Cookie::new("some", "cookie"))
+            .header(Header::new("X-Other", "value"))
+            .dispatch();
+        assert_eq!(response.body_string(), Some("violation".to_owned()));
     }
 
     #[test]
-    fn test_token_timeout() {
-        let rocket = default_rocket(default_builder().set_timeout(5).finalize().unwrap());
+    fn test_encoded_body() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
         let client = Client::new(rocket).expect("valid rocket instance");
 
-        let (token, cookie) = get_token(&client);
+        let mut response = client.get("/encoded/br").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_bytes(), Some(ENCODED_FORM.to_vec())); //unsupported coding, left unchanged
 
-        let mut response =
-            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("success".to_owned()));
-        ::std::thread::sleep(::std::time::Duration::from_secs(6));
+        #[cfg(feature = "compression")]
+        {
+            use compression::Encoding;
+            for coding in &["gzip", "deflate"] {
+                let mut response = client
+                    .get(format!("/encoded/{}", coding))
+                    .cookie(Cookie::new("some", "cookie"))
+                    .dispatch();
+                let encoding = Encoding::from_header(Some(coding)).unwrap();
+                let mut body = String::new();
+                encoding
+                    .decode(Box::new(Cursor::new(response.body_bytes().unwrap())))
+                    .read_to_string(&mut body)
+                    .unwrap();
+                assert!(body.starts_with("<form method=post><input type=\"hidden\" name=\"csrf-token\""));
+            }
+        }
+    }
 
-        //access / with timed out token
-        let mut response =
-            post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    #[test]
+    fn test_charset() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/charset/utf-16be").cookie(Cookie::new("some", "cookie")).dispatch();
+        let units: Vec<u16> = response
+            .body_bytes()
+            .unwrap()
+            .chunks(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect();
+        assert!(String::from_utf16(&units).unwrap().contains("csrf-token"));
+
+        let mut response = client.get("/charset/utf-32").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.body_bytes(), Some(utf16be_form())); //unsupported charset, left unchanged
+
+        for charset in &["shift_jis", "big5", "gbk"] {
+            let mut response = client
+                .get(format!("/multibyte/{}", charset))
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch();
+            let body = response.body_bytes().unwrap();
+            let (start, end) = MULTIBYTE_FORM.split_at(MULTIBYTE_FORM.len() - MULTIBYTE_FORM_END.len());
+            assert!(body.starts_with(start)); //trail bytes are not taken for an escape or a quote
+            assert!(body.ends_with(end));
+            assert!(String::from_utf8_lossy(&body[start.len()..]).starts_with("<input type=\"hidden\" name=\"csrf-token\""));
+        }
     }
 
     #[test]
-    fn test_invalid_token_pair() {
-        let rocket1 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
-        let client1 = Client::new(rocket1).expect("valid rocket instance");
-        let rocket2 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
-        let client2 = Client::new(rocket2).expect("valid rocket instance");
+    fn test_media_types() {
+        let client = |builder: CsrfFairingBuilder| {
+            Client::new(default_rocket(builder.finalize().unwrap())).expect("valid rocket instance")
+        };
+        let get = |client: &Client, path: &str| {
+            let mut response = client.get(path.to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+            assert!(!response.headers().contains(NO_INSERT_HEADER));
+            response.body_string().unwrap()
+        };
 
-        let (token, cookie) = get_token(&client1);
+        let default = client(default_builder());
+        assert_eq!(get(&default, "/xhtml"), XHTML_FORM); //not in the default media types
+        assert_eq!(get(&default, "/no-insert"), XHTML_FORM);
+        assert_eq!(get(&default, "/no-insert-wrapper"), XHTML_FORM);
 
-        //having only one part fail
-        let mut response =
-            post_token(&client2, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        let xhtml = client(default_builder().set_auto_insert_media_types(vec![
+            ContentType::HTML,
+            ContentType::new("application", "xhtml+xml"),
+        ]));
+        assert!(get(&xhtml, "/xhtml").contains("<input type=\"hidden\" name=\"csrf-token\""));
+        assert!(get(&xhtml, "/").contains("csrf-token"));
 
-        let mut response =
-            post_token(&client1, "/".to_owned(), "".to_owned(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        let disabled = client(default_builder().set_auto_insert(false));
+        assert_eq!(get(&disabled, "/no-insert"), XHTML_FORM);
+    }
 
-        let (token2, _cookie2) = get_token(&client2);
+    struct PathMeta(String);
 
-        //having 2 incompatible parts fail
-        let mut response =
-            post_token(&client1, "/".to_owned(), token2.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+    impl ElementHandler for PathMeta {
+        fn element(&mut self, element: &::rewriter::Element, rewrite: &mut ::rewriter::Rewrite) {
+            if element.name() == b"head" && !element.is_end_tag() {
+                rewrite.insert_after(format!("<meta name=\"path\" content=\"{}\"/>", self.0).as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_element_handlers() {
+        let client = |builder: CsrfFairingBuilder| {
+            let builder = builder.add_element_handler(|request| PathMeta(request.uri().path().to_owned()));
+            Client::new(default_rocket(builder.finalize().unwrap())).expect("valid rocket instance")
+        };
+        let meta = "<head><meta name=\"path\" content=\"/page\"/><title>";
+
+        let client = client(default_builder().set_auto_insert_meta(true));
+        let body = client.get("/page").dispatch().body_string().unwrap();
+        assert!(body.contains(meta)); //no cookie, so no token, but handlers still run
+        let body = client
+            .get("/page")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch()
+            .body_string()
+            .unwrap();
+        assert!(body.contains("<head><meta name=\"csrf-token\""));
+        assert!(body.contains("<meta name=\"path\" content=\"/page\"/><title>"));
+        assert_eq!(client.get("/no-insert").dispatch().body_string().unwrap(), XHTML_FORM);
     }
 
     #[test]
@@ -753,6 +2244,29 @@ How are you?
         );
     }
 
+    #[test]
+    fn test_auto_insert_exclusions() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_auto_insert_exclusions(vec![
+                    ("/<page>?embed=1".to_owned(), None),
+                    ("/".to_owned(), Some(Method::Post)),
+                ])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+        let get = |path: &str| {
+            let mut response = client.get(path.to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+            response.body_string().unwrap()
+        };
+
+        assert!(!get("/forms?embed=1").contains("csrf-token"));
+        assert!(!get("/forms?a=b&embed=1").contains("csrf-token"));
+        assert!(get("/forms?embed=0").contains("csrf-token"));
+        assert!(get("/").contains("csrf-token")); //only excluded for another method
+    }
+
     #[test]
     fn test_auto_insert_stream() {
         let rocket = default_rocket(
@@ -776,6 +2290,61 @@ How are you?
         //TODO test stream body
     }
 
+    #[test]
+    fn test_auto_insert_max_size() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_auto_insert_max_chunk_size(1024)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let body = client
+            .get("/long-tag")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch()
+            .body_string()
+            .unwrap(); //the tag is sent unmodified, but parsing goes on after it
+        assert_eq!(body.matches("<input type=\"hidden\" name=\"csrf-token\"").count(), 1);
+        assert!(body.ends_with("\"/></form>"));
+    }
+
+    #[test]
+    fn test_sized_body() {
+        let body_len = |builder: CsrfFairingBuilder, path: &str| {
+            let client = Client::new(default_rocket(builder.finalize().unwrap())).expect("valid rocket instance");
+            let mut response = client.get(path.to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
+            let len = match response.body() {
+                Some(Sized(_, len)) => Some(len),
+                _ => None,
+            };
+            let body = response.body_string().unwrap();
+            assert!(body.contains("<input type=\"hidden\" name=\"csrf-token\""));
+            len.map(|len| (len, body.len() as u64))
+        };
+
+        let (len, real_len) = body_len(default_builder(), "/forms").unwrap();
+        assert_eq!(len, real_len);
+        let builder = default_builder().set_auto_insert_max_chunk_size(16).set_auto_insert_max_sized_body(1024);
+        let (len, real_len) = body_len(builder, "/forms").unwrap();
+        assert_eq!(len, real_len);
+        let builder = default_builder()
+            .set_auto_insert_max_chunk_size(16)
+            .set_auto_insert_max_sized_body(64 * 1024)
+            .set_per_form_tokens(true);
+        let (len, real_len) = body_len(builder, "/long-tag").unwrap();
+        assert_eq!(len, real_len);
+        assert_eq!(body_len(default_builder().set_auto_insert_max_chunk_size(16), "/forms"), None); //follows the chunk size
+        let builder = default_builder().set_auto_insert_max_chunk_size(16).set_auto_insert_max_sized_body(64);
+        assert_eq!(body_len(builder, "/forms"), None); //streamed
+
+        //a body failing to be read is sent unchanged instead of panicking
+        let client = Client::new(default_rocket(default_builder().finalize().unwrap())).expect("valid rocket instance");
+        let mut response = client.get("/broken-body").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert!(!matches!(response.body(), Some(Sized(..))));
+    }
+
     #[test]
     fn test_key_from_env() {
         env::set_var(
@@ -901,4 +2470,144 @@ How are you?
             "<div><form method='POST'></form></div>",
         )
     }
+
+    #[get("/forms")]
+    fn forms() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            "<div><form method='POST' action='.'></form><form method=post action='ex2/some-url#frag'></form></div>",
+        )
+    }
+
+    #[get("/encoded-forms")]
+    fn encoded_forms() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            "<form method=post action=' &#47;ex2&#x2F;my page?a=1&amp;b=&lt;2&gt;&apos;'></form>\
+<form method=post action='ex2/caf\u{e9}'></form>",
+        )
+    }
+
+    struct BrokenBody(bool);
+
+    impl Read for BrokenBody {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            if self.0 {
+                Err(::std::io::Error::new(::std::io::ErrorKind::Other, "broken body"))
+            } else {
+                self.0 = true;
+                buf[..6].copy_from_slice(b"<form>");
+                Ok(6)
+            }
+        }
+    }
+
+    #[get("/broken-body")]
+    fn broken_body() -> Response<'static> {
+        Response::build()
+            .header(ContentType::HTML)
+            .raw_body(Sized(BrokenBody(false), 64))
+            .finalize()
+    }
+
+    #[get("/long-tag")]
+    fn long_tag() -> ::rocket::response::content::Content<String> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            format!("<div title='{}'></div><form method=post></form>", "<form method=post>".repeat(1000)),
+        )
+    }
+
+    #[get("/page")]
+    fn page() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            "<!DOCTYPE html><html><head><title>Page</title></head><body></body></html>",
+        )
+    }
+
+    const XHTML_FORM: &str = "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title/></head>\
+<body><form method=\"post\"><input name=\"a\"/></form></body></html>";
+
+    #[get("/xhtml")]
+    fn xhtml() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(ContentType::new("application", "xhtml+xml"), XHTML_FORM)
+    }
+
+    #[get("/no-insert")]
+    fn no_insert() -> ::rocket::Response<'static> {
+        ::rocket::Response::build()
+            .header(ContentType::HTML)
+            .raw_header(NO_INSERT_HEADER, "1")
+            .sized_body(Cursor::new(XHTML_FORM))
+            .finalize()
+    }
+
+    #[get("/no-insert-wrapper")]
+    fn no_insert_wrapper() -> ::NoCsrfInsert<::rocket::response::content::Html<&'static str>> {
+        ::NoCsrfInsert(::rocket::response::content::Html(XHTML_FORM))
+    }
+
+    fn utf16be_form() -> Vec<u8> {
+        "<form method=post></form>"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    #[get("/charset/<charset>")]
+    fn charset(charset: String) -> ::rocket::Response<'static> {
+        ::rocket::Response::build()
+            .raw_header("Content-Type", format!("text/html; charset={}", charset))
+            .sized_body(Cursor::new(utf16be_form()))
+            .finalize()
+    }
+
+    // markup holding characters whose trail byte is a backslash in these charsets
+    const MULTIBYTE_FORM: &[u8] =
+        b"<p title=\"\x95\x5c\">\xb3\x5c</p><form method=post action=\"/\x83\x5c\" class=\x95\x5c><input value=\"\xb3\x5c\"></form>";
+    const MULTIBYTE_FORM_END: &[u8] = b"<input value=\"\xb3\x5c\"></form>";
+
+    #[get("/multibyte/<charset>")]
+    fn multibyte(charset: String) -> ::rocket::Response<'static> {
+        ::rocket::Response::build()
+            .raw_header("Content-Type", format!("text/html; charset={}", charset))
+            .sized_body(Cursor::new(MULTIBYTE_FORM))
+            .finalize()
+    }
+
+    const ENCODED_FORM: &[u8] = b"<form method=post></form>";
+
+    #[get("/encoded/<coding>")]
+    fn encoded(coding: String) -> ::rocket::Response<'static> {
+        let body = match ::compression::Encoding::from_header(Some(&coding)) {
+            Some(encoding) => {
+                let mut body = Vec::new();
+                encoding.encode(Box::new(ENCODED_FORM)).read_to_end(&mut body).unwrap();
+                body
+            }
+            None => ENCODED_FORM.to_vec(), //pretend it is encoded
+        };
+        ::rocket::Response::build()
+            .header(::rocket::http::ContentType::HTML)
+            .raw_header("Content-Encoding", coding)
+            .sized_body(Cursor::new(body))
+            .finalize()
+    }
+
+    #[get("/htmx-forms")]
+    fn htmx_forms() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            "<div><form hx-put='/'><input name=a></form><form><button formmethod=post>Post</button></form></div>",
+        )
+    }
+
+    #[get("/external-forms")]
+    fn external_forms() -> ::rocket::response::content::Content<&'static str> {
+        ::rocket::response::content::Content(
+            ::rocket::http::ContentType::HTML,
+            "<div><form method='POST' action='https://payment.example.org/'></form><form method=post action='https://example.com/ex2'></form></div>",
+        )
+    }
 }
@@ -1,312 +1,225 @@
 use csrf_proxy::ParseState::*;
-use std::cmp;
-use std::collections::VecDeque;
-use std::io::{Error, Read};
-
-#[derive(Debug)]
-struct Buffer {
-    buf: VecDeque<Vec<u8>>,
-    pos: VecDeque<usize>,
+use rewriter::{Element, ElementHandler, Rewrite};
+use utils::is_own_origin;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseState {
+    Init,        //default state, searching for a form
+    InForm,      //inside a form, searching where to insert a token
+    InOtherForm, //inside a form not needing a token, searching for a button overriding its method
 }
 
-impl Buffer {
-    fn new() -> Self {
-        Buffer {
-            buf: VecDeque::new(),
-            pos: VecDeque::new(),
-        }
-    }
+// where to insert a token relatively to the tag being parsed
+enum Insertion {
+    Before,
+    After,
+}
 
-    fn push_back(&mut self, value: Vec<u8>) {
-        self.buf.push_back(value);
-        self.pos.push_back(0);
-    }
+/// Which forms get a token inserted
+#[derive(Clone, Debug)]
+pub struct FormMatcher {
+    pub methods: Vec<Vec<u8>>, //uppercase methods of forms getting a token
+    pub action_attributes: Vec<(Vec<u8>, Vec<u8>)>, //lowercase attributes giving both the action and method of a form, like hx-post
+    pub formmethod: bool, //if forms with a button whose formmethod is one of methods get a token
+}
 
-    fn read(&mut self, buf: &mut [u8]) -> usize {
-        let mut read = 0;
-        while buf.len() > read && !self.is_empty() {
-            let part_len = self.buf[0].len() - self.pos[0];
-            let buf_len = buf.len() - read;
-            let to_copy = cmp::min(part_len, buf_len);
-            buf[read..read + to_copy]
-                .copy_from_slice(&self.buf[0][self.pos[0]..self.pos[0] + to_copy]);
-            read += to_copy;
-            if part_len == to_copy {
-                self.buf.pop_front();
-                self.pos.pop_front();
-            } else {
-                self.pos[0] += to_copy;
-            }
+impl Default for FormMatcher {
+    fn default() -> Self {
+        FormMatcher {
+            methods: vec![b"POST".to_vec()],
+            action_attributes: Vec::new(),
+            formmethod: false,
         }
-        read
     }
+}
 
-    fn len(&self) -> usize {
-        let len: usize = self.buf.iter().map(Vec::len)
-            .sum();
-        let pos: usize = self.pos.iter().sum();
-        len - pos
+impl FormMatcher {
+    // get the method and action of a form if it needs a token
+    fn match_form(&self, tag: &Element) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.action_attributes
+            .iter()
+            .find_map(|(name, method)| tag.attribute(name).map(|action| (method.to_vec(), action.to_vec())))
+            .or_else(|| {
+                let method = tag.attribute(b"method").unwrap_or_default().to_ascii_uppercase();
+                if self.methods.contains(&method) {
+                    Some((method, tag.attribute(b"action").unwrap_or_default().to_vec()))
+                } else {
+                    None
+                }
+            })
     }
 
-    fn is_empty(&self) -> bool {
-        self.buf.is_empty()
+    // get the method a button submits its form with, if it needs a token
+    fn match_button(&self, tag: &Element) -> Option<Vec<u8>> {
+        if !self.formmethod || !(tag.name() == b"button" || tag.name() == b"input") {
+            return None;
+        }
+        let method = tag.attribute(b"formmethod")?.to_ascii_uppercase();
+        if self.methods.contains(&method) {
+            Some(method)
+        } else {
+            None
+        }
     }
 }
 
-impl Default for Buffer {
-    fn default() -> Self {
-        Self::new()
-    }
+/// Generate the token to insert into a form, given its method and action
+type TokenGenerator<'a> = Box<dyn FnMut(&[u8], &[u8]) -> Vec<u8> + 'a>;
+
+/// Element handler inserting tokens into forms
+pub struct CsrfInserter<'a> {
+    token: Option<TokenGenerator<'a>>, //generate tokens to insert in forms, if they differ
+    tag: Vec<u8>,                      //hidden input holding the token, built once if it never change
+    form: (Vec<u8>, Vec<u8>),          //method and action of the current form
+    origins: Vec<String>,      //origins other than the page one forms may post tokens to
+    forms: FormMatcher,
+    meta: Option<Vec<u8>>, //token to insert in a meta tag at the start of the head
+    state: ParseState,     //state of the parser
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum ParseState {
-    Init,                    //default state
-    PartialFormMatch,        //when parsing "<form"
-    SearchMethodForm,        //when searching for a method=\"POST\" tag
-    MatchingMethodForm,      //when matching against method=\"POST\"
-    SearchFormElem,          //like default state, but inside a form
-    PartialFormElemMatch,    //when parsing "<input", "<textarea" or other form elems, and "</form"
-    SearchMethod(usize),     //when inside the first <input>, search for begining of a param
-    PartialNameMatch(usize), //when parsing "name="_method""
-    CloseInputTag,           //only if insert after, search for '>' of a "<input name=\"_method\">"
-}
+impl<'a> CsrfInserter<'a> {
+    /// Create an inserter putting the same token in every form
+    pub fn new(token: &[u8]) -> Self {
+        let mut inserter = Self::build(None);
+        token_tag(&mut inserter.tag, token);
+        inserter
+    }
 
-pub struct CsrfProxy<'a> {
-    underlying: Box<dyn Read + 'a>, //the underlying Reader from which we get data
-    token: Vec<u8>,                 //a full input tag loaded with a valid token
-    buf: Buffer,
-    unparsed: Vec<u8>,
-    state: ParseState, //state of the parser
-    eof: bool,
-}
+    /// Create an inserter putting a different token in each form
+    pub fn with_generator<F>(token: F) -> Self
+    where
+        F: FnMut(&[u8], &[u8]) -> Vec<u8> + 'a,
+    {
+        Self::build(Some(Box::new(token)))
+    }
 
-impl<'a> CsrfProxy<'a> {
-    pub fn from(underlying: Box<dyn Read + 'a>, token: &[u8]) -> Self {
-        let tag_begin = b"<input type=\"hidden\" name=\"csrf-token\" value=\"";
-        let tag_middle = token;
-        let tag_end = b"\"/>";
-        let mut token = Vec::new();
-        token.extend_from_slice(tag_begin);
-        token.extend_from_slice(tag_middle);
-        token.extend_from_slice(tag_end);
-        CsrfProxy {
-            underlying,
+    fn build(token: Option<TokenGenerator<'a>>) -> Self {
+        CsrfInserter {
             token,
-            buf: Buffer::new(),
-            unparsed: Vec::with_capacity(4096),
+            tag: Vec::new(),
+            form: (Vec::new(), Vec::new()),
+            origins: Vec::new(),
+            forms: FormMatcher::default(),
+            meta: None,
             state: ParseState::Init,
-            eof: false,
+        }
+    }
+
+    /// Set origins, such as `https://example.com`, to which forms with an absolute action may
+    /// be submitted with a token. Other forms only get a token if their action is relative.
+    pub fn with_origins(mut self, origins: Vec<String>) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// Set which forms get a token, instead of only those with a POST method.
+    pub fn with_forms(mut self, forms: FormMatcher) -> Self {
+        self.forms = forms;
+        self
+    }
+
+    /// Also insert `token` in a `<meta name="csrf-token">` tag right after the opening tag of the
+    /// head of the document, for scripts to read it.
+    pub fn with_meta(mut self, token: &[u8]) -> Self {
+        self.meta = Some(token.to_vec());
+        self
+    }
+
+    // insert the token of the current form
+    fn insert_token(&mut self, insertion: Insertion, rewrite: &mut Rewrite) {
+        if let Some(ref mut generator) = self.token {
+            let token = generator(&self.form.0, &self.form.1);
+            token_tag(&mut self.tag, &token);
+        }
+        match insertion {
+            Insertion::Before => rewrite.insert_before(&self.tag),
+            Insertion::After => rewrite.insert_after(&self.tag),
         }
     }
 }
 
-impl<'a> Read for CsrfProxy<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        while self.buf.len() < buf.len() && !(self.eof && self.unparsed.is_empty()) {
-            let len = if !self.eof || self.state == Init {
-                let unparsed_len = self.unparsed.len();
-                let mut new_unparsed_len = 4096;
-                while unparsed_len >= new_unparsed_len {
-                    new_unparsed_len += 4096;
-                }
-                self.unparsed.resize(new_unparsed_len, 0);
-                unparsed_len + match self.underlying.read(&mut self.unparsed[unparsed_len..]) {
-                    Ok(0) => {
-                        self.eof = true;
-                        0
+impl<'a> ElementHandler for CsrfInserter<'a> {
+    fn element(&mut self, tag: &Element, rewrite: &mut Rewrite) {
+        match self.state {
+            Init => {
+                if !tag.is_end_tag() && tag.name() == b"head" {
+                    if let Some(token) = self.meta.take() {
+                        rewrite.insert_after(&meta_tag(&token));
+                        return; //only in the first head
                     }
-                    Ok(len) => len,
-                    Err(e) => return Err(e),
                 }
-            } else {
-                let offset = self.buf.read(buf);
-                let unparsed_len = cmp::min(buf.len() - offset, self.unparsed.len());
-                buf[offset..offset + unparsed_len].copy_from_slice(&self.unparsed[0..unparsed_len]);
-                self.unparsed = self.unparsed[unparsed_len..].to_vec();
-                return Ok(unparsed_len + offset);
-            };
-
-            self.unparsed.resize(len, 0); //we growed unparsed buffer to 4k before, so shrink it to it's needed size
-
-            let (consumed, insert_token) = {
-                let mut buf = &self.unparsed[..len]; //work only on the initialized part
-                let mut consumed = 0;
-                let mut leave = false;
-                let mut insert_token = false;
-                while !leave {
-                    self.state = match self.state {
-                        Init => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                PartialFormMatch
-                            } else {
-                                leave = true;
-                                consumed += buf.len();
-                                Init
-                            }
-                        }
-                        PartialFormMatch => {
-                            if let Some(lower_begin) =
-                                buf.get(1..5).map(|slice| slice.to_ascii_lowercase())
-                            {
-                                buf = &buf[5..];
-                                consumed += 5;
-                                if lower_begin == b"form" {
-                                    SearchMethodForm
-                                } else {
-                                    Init
-                                }
-                            } else {
-                                leave = true;
-                                PartialFormMatch
-                            }
-                        }
-                        SearchMethodForm => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == ' ' || c as char == '>') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                if buf[0] as char == ' ' {
-                                    MatchingMethodForm
-                                } else {
-                                    Init
-                                }
-                            } else {
-                                leave = true;
-                                consumed += buf.len();
-                                SearchMethodForm
-                            }
-                        }
-                        MatchingMethodForm => {
-                            if let Some(lower_method) =
-                                buf.get(1..14).map(|slice| slice.to_ascii_lowercase())
-                            {
-                                buf = &buf[1..];
-                                consumed+=1;
-                                if lower_method.starts_with(b"method=post") {
-                                    buf = &buf[11..];
-                                    consumed += 11;
-                                    SearchFormElem
-                                } else if lower_method.starts_with(b"method=\"post\"")
-                                    || lower_method.starts_with(b"method='post'")
-                                {
-                                    buf = &buf[13..];
-                                    consumed += 13;
-                                    SearchFormElem
-                                } else {
-                                    SearchMethodForm
-                                }
-                            } else {
-                                leave = true;
-                                MatchingMethodForm
-                            }
+                if !tag.is_end_tag() && tag.name() == b"form" {
+                    match self.forms.match_form(tag) {
+                        Some((method, action)) => {
+                            if is_own_origin(&String::from_utf8_lossy(&action), &self.origins) {
+                                self.form = (method, action);
+                                self.state = InForm;
+                            } //never leak a token to another site
                         }
-                        SearchFormElem => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                PartialFormElemMatch
-                            } else {
-                                leave = true;
-                                consumed += buf.len();
-                                SearchFormElem
-                            }
-                        }
-                        PartialFormElemMatch => {
-                            if let Some(lower_begin) =
-                                buf.get(1..9).map(|slice| slice.to_ascii_lowercase())
-                            {
-                                if lower_begin.starts_with(b"/form")
-                                    || lower_begin.starts_with(b"textarea")
-                                    || lower_begin.starts_with(b"button")
-                                    || lower_begin.starts_with(b"select")
-                                {
-                                    insert_token = true;
-                                    leave = true;
-                                    Init
-                                } else if lower_begin.starts_with(b"input") {
-                                    SearchMethod(6)
-                                } else {
-                                    buf = &buf[9..];
-                                    consumed += 9;
-                                    SearchFormElem
-                                }
-                            } else {
-                                leave = true;
-                                PartialFormElemMatch
-                            }
-                        }
-                        SearchMethod(pos) => {
-                            if let Some(meth_pos) = buf[pos..]
-                                .iter()
-                                .position(|&c| c as char == ' ' || c as char == '>')
-                            {
-                                if buf[meth_pos + pos] as char == ' ' {
-                                    PartialNameMatch(meth_pos + pos + 1)
-                                } else {
-                                    //reached '>'
-                                    insert_token = true;
-                                    leave = true;
-                                    Init
-                                }
-                            } else {
-                                leave = true;
-                                SearchMethod(buf.len())
-                            }
-                        }
-                        PartialNameMatch(pos) => {
-                            if let Some(lower_begin) = buf
-                                .get(pos..pos + 14)
-                                .map(|slice| slice.to_ascii_lowercase())
-                            {
-                                if lower_begin.starts_with(b"name=\"_method\"")
-                                    || lower_begin.starts_with(b"name='_method'")
-                                {
-                                    buf = &buf[pos + 14..];
-                                    consumed += pos + 14;
-                                    CloseInputTag
-                                } else if lower_begin.starts_with(b"name=_method") {
-                                    buf = &buf[pos + 12..];
-                                    consumed += pos + 12;
-                                    CloseInputTag
-                                } else {
-                                    SearchMethod(pos)
-                                }
-                            } else {
-                                leave = true;
-                                PartialNameMatch(pos)
-                            }
-                        }
-                        CloseInputTag => {
-                            leave = true;
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '>') {
-                                buf = &buf[tag_pos + 1..];
-                                consumed += tag_pos + 1;
-                                insert_token = true;
-                                Init
-                            } else {
-                                consumed += buf.len();
-                                CloseInputTag
-                            }
+                        None if self.forms.formmethod => {
+                            self.form = (Vec::new(), tag.attribute(b"action").unwrap_or_default().to_vec());
+                            self.state = InOtherForm;
                         }
+                        None => {}
+                    }
+                }
+            }
+            InOtherForm => {
+                if tag.is_end_tag() && tag.name() == b"form" {
+                    self.state = Init;
+                } else if let (Some(method), false) = (self.forms.match_button(tag), tag.is_end_tag()) {
+                    let action = match tag.attribute(b"formaction") {
+                        Some(action) => action.to_vec(),
+                        None => self.form.1.clone(),
+                    };
+                    if is_own_origin(&String::from_utf8_lossy(&action), &self.origins) {
+                        self.state = Init;
+                        self.form = (method, action);
+                        self.insert_token(Insertion::Before, rewrite);
                     }
                 }
-                (consumed, insert_token)
-            };
-            self.buf.push_back(self.unparsed[0..consumed].to_vec());
-            if insert_token {
-                self.buf.push_back(self.token.clone());
             }
-            self.unparsed.drain(0..consumed);
+            InForm => {
+                let insertion = match (tag.name(), tag.is_end_tag()) {
+                    (b"input", false) => {
+                        //an unquoted value keep a trailing '/', but <input name=_method/> is
+                        //most likely meant as a self-closing tag
+                        let name = tag.attribute(b"name").map(|name| name.strip_suffix(b"/").unwrap_or(name));
+                        if name.map_or(false, |name| name.eq_ignore_ascii_case(b"_method")) {
+                            Insertion::After
+                        } else {
+                            Insertion::Before
+                        }
+                    }
+                    (b"textarea", false) | (b"button", false) | (b"select", false) | (b"form", true) => {
+                        Insertion::Before
+                    }
+                    _ => return,
+                };
+                self.state = Init;
+                self.insert_token(insertion, rewrite);
+            }
         }
-        Ok(self.buf.read(buf))
     }
 }
 
+// build the hidden input holding a token into tag, reusing its memory
+fn token_tag(tag: &mut Vec<u8>, token: &[u8]) {
+    tag.clear();
+    tag.extend_from_slice(b"<input type=\"hidden\" name=\"csrf-token\" value=\"");
+    tag.extend_from_slice(token);
+    tag.extend_from_slice(b"\"/>");
+}
+
+fn meta_tag(token: &[u8]) -> Vec<u8> {
+    [&b"<meta name=\"csrf-token\" content=\""[..], token, b"\"/>"].concat()
+}
+
 #[cfg(test)]
 mod tests {
-    use csrf_proxy::{Buffer, CsrfProxy};
+    use charset::{Charset, Endian};
+    use csrf_proxy::{CsrfInserter, FormMatcher};
+    use rewriter::HtmlRewriter;
     use std::io::{Cursor, Read};
 
     macro_rules! must_finish {
@@ -323,54 +236,6 @@ mod tests {
         }};
     }
 
-    #[test]
-    fn test_buffer_size() {
-        let mut buffer = Buffer::new();
-        assert!(buffer.is_empty());
-        assert_eq!(buffer.len(), 0);
-
-        buffer.push_back(vec![0; 64]);
-        assert!(!buffer.is_empty());
-        assert_eq!(buffer.len(), 64);
-        let mut buf = [0; 32];
-        buffer.read(&mut buf);
-        assert!(!buffer.is_empty());
-        assert_eq!(buffer.len(), 32);
-        buffer.read(&mut buf);
-        assert!(buffer.is_empty());
-        assert_eq!(buffer.len(), 0);
-    }
-
-    #[test]
-    fn test_buffer_integrity() {
-        let mut buffer = Buffer::new();
-        let mut buf = [0; 8];
-
-        buffer.push_back(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
-        buffer.push_back(vec![10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
-
-        let size = buffer.read(&mut buf);
-        assert_eq!(size, 8);
-        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
-
-        buffer.push_back(vec![20, 21, 22, 23, 24, 25, 26, 27, 28, 29]);
-
-        let size = buffer.read(&mut buf);
-        assert_eq!(size, 8);
-        assert_eq!(buf, [8, 9, 10, 11, 12, 13, 14, 15]);
-
-        let size = buffer.read(&mut buf);
-        assert_eq!(size, 8);
-        assert_eq!(buf, [16, 17, 18, 19, 20, 21, 22, 23]);
-
-        let size = buffer.read(&mut buf);
-        assert_eq!(size, 6);
-        assert_eq!(buf[..6], [24, 25, 26, 27, 28, 29]);
-
-        let size = buffer.read(&mut buf);
-        assert_eq!(size, 0);
-    }
-
     #[test]
     fn test_proxy_identity() {
         must_finish!{{
@@ -383,7 +248,7 @@ mod tests {
     Body of this simple doc
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(read.unwrap(), data.len());
@@ -420,7 +285,7 @@ mod tests {
      <input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -456,7 +321,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -492,7 +357,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -522,7 +387,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -533,6 +398,158 @@ mod tests {
         }}
     }
 
+    #[test]
+    fn test_token_generator() {
+        must_finish!{{
+            let data = b"<form action=\"/first\" method=\"POST\"><input name=\"a\"/></form>\
+<form method='get' action='/get'></form><form method=post><select></select></form>";
+            let expected = b"<form action=\"/first\" method=\"POST\">\
+<input type=\"hidden\" name=\"csrf-token\" value=\"POST /first\"/><input name=\"a\"/></form>\
+<form method='get' action='/get'></form><form method=post>\
+<input type=\"hidden\" name=\"csrf-token\" value=\"POST \"/><select></select></form>";
+            let mut proxy = HtmlRewriter::new(
+                Box::new(SlowReader { content: data }),
+                CsrfInserter::with_generator(|method, action| [method, b" ", action].concat()),
+            );
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            assert_eq!(pr_data[..], expected[..])
+        }}
+    }
+
+    #[test]
+    fn test_form_variants() {
+        must_finish!{{
+            let data = b"<form\n  method = \"POST\"><input name=a></form>\
+<form data-x=\"a>b\" method='post'><textarea></textarea></form>\
+<FORM METHOD=Post action=/x><BUTTON></BUTTON></FORM>\
+<form method=\"get\" data-method=post><input></form>\
+<form method=post></form >";
+            let expected = b"<form\n  method = \"POST\"><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/><input name=a></form>\
+<form data-x=\"a>b\" method='post'><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/><textarea></textarea></form>\
+<FORM METHOD=Post action=/x><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/><BUTTON></BUTTON></FORM>\
+<form method=\"get\" data-method=post><input></form>\
+<form method=post><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form >";
+            let check = |reader: Box<dyn Read>| {
+                let mut proxy = proxy_from(reader, b"abcd");
+                let mut pr_data = Vec::new();
+                proxy.read_to_end(&mut pr_data).unwrap();
+                assert_eq!(::std::str::from_utf8(&pr_data).unwrap(), ::std::str::from_utf8(&expected[..]).unwrap());
+            };
+            check(Box::new(Cursor::new(&data[..])));
+            check(Box::new(SlowReader { content: data }));
+        }}
+    }
+
+    #[test]
+    fn test_form_matcher() {
+        must_finish!({
+            let data = b"<form method=put action=/put><input></form>\
+<form hx-patch=\"/patch\" method=post><input></form>\
+<form method=delete><input></form>\
+<form action=/search><input name=q><button formmethod=post formaction=/save>Save</button></form>\
+<form action=/search><input name=q><button formmethod=post formaction=https://evil.com>x</button>\
+<button formmethod=post>Post</button></form>\
+<form><input><button>Search</button></form>";
+            let expected = b"<form method=put action=/put><input type=\"hidden\" name=\"csrf-token\" value=\"PUT /put\"/><input></form>\
+<form hx-patch=\"/patch\" method=post><input type=\"hidden\" name=\"csrf-token\" value=\"PATCH /patch\"/><input></form>\
+<form method=delete><input></form>\
+<form action=/search><input name=q><input type=\"hidden\" name=\"csrf-token\" value=\"POST /save\"/>\
+<button formmethod=post formaction=/save>Save</button></form>\
+<form action=/search><input name=q><button formmethod=post formaction=https://evil.com>x</button>\
+<input type=\"hidden\" name=\"csrf-token\" value=\"POST /search\"/><button formmethod=post>Post</button></form>\
+<form><input><button>Search</button></form>";
+            let forms = FormMatcher {
+                methods: vec![b"POST".to_vec(), b"PUT".to_vec()],
+                action_attributes: vec![(b"hx-patch".to_vec(), b"PATCH".to_vec())],
+                formmethod: true,
+            };
+            let inserter = CsrfInserter::with_generator(|method, action| [method, b" ", action].concat()).with_forms(forms);
+            let mut proxy = HtmlRewriter::new(Box::new(SlowReader { content: data }), inserter);
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            assert_eq!(String::from_utf8_lossy(&pr_data), String::from_utf8_lossy(expected));
+        })
+    }
+
+    #[test]
+    fn test_xml_syntax() {
+        must_finish!({
+            let data = b"<?xml version=\"1.0\"?><html xmlns=\"http://www.w3.org/1999/xhtml\"><head>\
+<title/><script src=\"a.js\"/><script><![CDATA[ if (a < b) { f('</script>'); } ]]></script></head>\
+<body><template/><form method=\"post\"><input name=\"a\"/></form></body></html>";
+            let token = b"<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>";
+            let mut proxy = proxy_from(Box::new(SlowReader { content: data }), b"abcd").with_xml_syntax(true);
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            let pos = data.windows(15).position(|w| w == b"<input name=\"a\"").unwrap();
+            assert_eq!(pr_data, [&data[..pos], &token[..], &data[pos..]].concat());
+
+            //as html, <title/> starts a title which never ends
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            assert_eq!(pr_data[..], data[..]);
+        })
+    }
+
+    #[test]
+    fn test_meta_tag() {
+        must_finish!({
+            let data = b"<!DOCTYPE html><html><!-- <head> --><HEAD lang=en><title><head></title></HEAD>\
+<body><head></head><form method=post></form></body></html>";
+            let expected = b"<!DOCTYPE html><html><!-- <head> --><HEAD lang=en>\
+<meta name=\"csrf-token\" content=\"abcd\"/><title><head></title></HEAD>\
+<body><head></head><form method=post><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form></body></html>";
+            let mut proxy = HtmlRewriter::new(
+                Box::new(SlowReader { content: data }),
+                CsrfInserter::new(b"abcd").with_meta(b"abcd"),
+            );
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            assert_eq!(String::from_utf8_lossy(&pr_data), String::from_utf8_lossy(expected));
+        })
+    }
+
+    #[test]
+    fn test_foreign_origin() {
+        must_finish!({
+            let data = b"<form method=post action=\"https://payment.example.org/checkout\"><input></form>\
+<form method=post action=\"https://example.com/local\"><input></form>\
+<form method=post action='//evil.com'><input></form>\
+<form method=post action=\"/\\evil.com\"><input></form>\
+<form method=post action=\"/local\"><input></form>";
+            let expected = b"<form method=post action=\"https://payment.example.org/checkout\"><input></form>\
+<form method=post action=\"https://example.com/local\"><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/><input></form>\
+<form method=post action='//evil.com'><input></form>\
+<form method=post action=\"/\\evil.com\"><input></form>\
+<form method=post action=\"/local\"><input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/><input></form>";
+            let inserter = CsrfInserter::new(b"abcd").with_origins(vec!["https://example.com".to_owned()]);
+            let mut proxy = HtmlRewriter::new(Box::new(Cursor::new(&data[..])), inserter);
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).unwrap();
+            assert_eq!(String::from_utf8_lossy(&pr_data), String::from_utf8_lossy(expected));
+        })
+    }
+
+    #[test]
+    fn test_raw_text_content() {
+        must_finish!({
+            let data = std::fs::read_to_string("tests/raw-text-content.html").unwrap();
+            let tag = r#"<input type="hidden" name="csrf-token" value="abcd"/>"#;
+            let check = |reader: Box<dyn Read>| {
+                let mut proxy = proxy_from(reader, b"abcd");
+                let mut pr_data = String::new();
+                proxy.read_to_string(&mut pr_data).unwrap();
+                assert_eq!(pr_data.matches(tag).count(), 1); //only the real form get a token
+                assert!(pr_data.contains(&format!("{}<button>Real form</button>", tag)));
+                assert_eq!(pr_data.replace(tag, ""), data);
+            };
+            check(Box::new(Cursor::new(data.clone().into_bytes())));
+            check(Box::new(SlowReader { content: data.as_bytes() }));
+        })
+    }
+
     struct ErrorReader {}
 
     impl Read for ErrorReader {
@@ -546,7 +563,7 @@ mod tests {
         must_finish!{{
             let buf = &mut [0; 1];
             let err = ErrorReader {};
-            let mut proxy_err = CsrfProxy::from(Box::new(err), &[0]);
+            let mut proxy_err = proxy_from(Box::new(err), &[0]);
             let read = proxy_err.read(buf).unwrap_err();
             assert_eq!(
                 read.kind(),
@@ -555,13 +572,18 @@ mod tests {
         }}
     }
 
+    // rewriter inserting the same token in every form
+    fn proxy_from<'a>(underlying: Box<dyn Read + 'a>, token: &[u8]) -> HtmlRewriter<'a, CsrfInserter<'a>> {
+        HtmlRewriter::new(underlying, CsrfInserter::new(token))
+    }
+
     struct SlowReader<'a> {
         content: &'a [u8],
     }
 
     impl<'a> Read for SlowReader<'a> {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize, ::std::io::Error> {
-            if self.content.len() > 0 {
+            if !self.content.is_empty() {
                 buf[0] = self.content[0];
                 self.content = &self.content[1..];
                 Ok(1)
@@ -601,7 +623,7 @@ mod tests {
      <input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = proxy_from(Box::new(SlowReader { content: data }), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -633,7 +655,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = proxy_from(Box::new(SlowReader { content: data }), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -665,7 +687,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = proxy_from(Box::new(SlowReader { content: data }), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -697,7 +719,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = proxy_from(Box::new(SlowReader { content: data }), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(
@@ -722,7 +744,7 @@ mod tests {
           some text
         </p>";
 
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(read.unwrap(), data.len());
@@ -746,7 +768,7 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = Vec::new();
             let read = proxy.read_to_end(&mut pr_data);
             assert_eq!(read.unwrap(), data.len());
@@ -758,7 +780,7 @@ mod tests {
     fn test_persian_content() {
         must_finish!({
             let data = std::fs::read_to_string("tests/persian-content.html").unwrap();
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = proxy_from(Box::new(Cursor::new(&data[..])), b"abcd");
             let mut pr_data = String::new();
             let read = proxy.read_to_string(&mut pr_data);
 
@@ -768,4 +790,39 @@ mod tests {
             assert!(pr_len - data_len > min_diff);
         })
     }
+
+    #[test]
+    fn test_utf16_content() {
+        must_finish!({
+            let persian = std::fs::read_to_string("tests/persian-content.html").unwrap();
+            let short = "<form method=post>🦀<input></form>".to_owned();
+            for &(bom, endian) in &[(&[0xFF, 0xFE], Endian::Little), (&[0xFE, 0xFF], Endian::Big)] {
+                let encode = |text: &str| -> Vec<u8> {
+                    let units = text.encode_utf16().flat_map(|unit| match endian {
+                        Endian::Little => unit.to_le_bytes(),
+                        Endian::Big => unit.to_be_bytes(),
+                    });
+                    bom.iter().cloned().chain(units).collect()
+                };
+                for &(data, slow) in &[(&persian, false), (&short, true)] {
+                    let mut expected = String::new();
+                    proxy_from(Box::new(Cursor::new(&data[..])), b"abcd")
+                        .read_to_string(&mut expected)
+                        .unwrap();
+                    let encoded = encode(data);
+                    let reader: Box<dyn Read> = if slow {
+                        Box::new(SlowReader { content: &encoded }) //cut inside characters
+                    } else {
+                        Box::new(Cursor::new(&encoded[..]))
+                    };
+                    let mut pr_data = Vec::new();
+                    Charset::Utf16(None)
+                        .transcode(reader, |body| Box::new(proxy_from(body, b"abcd")))
+                        .read_to_end(&mut pr_data)
+                        .unwrap();
+                    assert_eq!(pr_data, encode(&expected));
+                }
+            }
+        })
+    }
 }
@@ -1,13 +1,16 @@
 use CSRF_COOKIE_NAME;
 use data_encoding::BASE64URL_NOPAD;
-use rocket::http::{Cookie, SameSite, Status};
+use rocket::http::{Cookie, Method, SameSite, Status};
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest};
 use rocket::{Request, State};
 use serde::{Serialize, Serializer};
+use std::fmt;
 use time::Duration;
 
-use crypto::CsrfProtection;
+use crypto::{CsrfCookie, CsrfProtection, PAIR_SIZE};
+use csrf_fairing::CsrfState;
+use utils::resolve_uri;
 
 /// Csrf token to insert into pages.
 ///
@@ -16,9 +19,12 @@ use crypto::CsrfProtection;
 /// This impltement Serde's Serialize so you may insert it directly into your templats as if it was
 /// a String. It also implement FromRequest so you can get it as a request guard. This is also the
 /// only way to get this struct.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CsrfToken {
     value: String,
+    engine: CsrfProtection,
+    tag: Vec<u8>,
+    session: Vec<u8>,
 }
 
 impl CsrfToken {
@@ -26,6 +32,46 @@ impl CsrfToken {
     pub fn value(&self) -> &[u8] {
         self.value.as_bytes()
     }
+
+    /// Obtain a token bound to a form, to use instead of [`value`] when per-form tokens are
+    /// enabled on the fairing. `action` is the path (and query) the form is submitted to, relative
+    /// paths being resolved from the root.
+    ///
+    /// Should binding the token fail, a warning is logged and the unbound [`value`] is returned
+    /// instead, which is rejected by routes expecting a per-form token.
+    ///
+    /// [`value`]: #method.value
+    pub fn for_form(&self, method: Method, action: &str) -> String {
+        self.bound_value(method.as_str().as_bytes(), &resolve_uri("/", action))
+    }
+
+    pub(crate) fn bound_value(&self, method: &[u8], action: &str) -> String {
+        self.engine
+            .seal_token(&self.tag, &form_aad(&self.session, method, action))
+            .and_then(|token| self.engine.mask_token(&token))
+            .map(|token| BASE64URL_NOPAD.encode(&token))
+            .unwrap_or_else(|err| {
+                warn!("[rocket_csrf] Can't bind a token to a form, falling back to an unbound one: {:?}", err);
+                self.value.clone()
+            })
+    }
+}
+
+impl fmt::Debug for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CsrfToken").field("value", &self.value).finish()
+    }
+}
+
+/// Associated data binding a token to a session, and to the method and action of a form
+pub fn form_aad(session: &[u8], method: &[u8], action: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(8 + session.len() + method.len() + 1 + action.len());
+    aad.extend_from_slice(&(session.len() as u64).to_be_bytes());
+    aad.extend_from_slice(session);
+    aad.extend_from_slice(method);
+    aad.push(b' ');
+    aad.extend_from_slice(action.as_bytes());
+    aad
 }
 
 impl Serialize for CsrfToken {
@@ -41,24 +87,54 @@ impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
-        let (csrf_engine, duration) = request
-            .guard::<State<(CsrfProtection, u64)>>()
-            .unwrap()
-            .inner();
+        let state = request.guard::<State<CsrfState>>().unwrap().inner();
+        let (csrf_engine, duration) = (&state.engine, &state.duration);
+        let session = state.session_id(request); //must be done before borrowing cookies
+        let binding = state.binding(request, &session);
 
         let mut cookies = request.cookies();
-        if cookies.iter().count() == 0
-            || cookies.iter().count() == 1 && cookies.get(CSRF_COOKIE_NAME).is_some()
+        if cookies.iter().all(|cookie| {
+            cookie.name() == CSRF_COOKIE_NAME || state.token_cookie.as_ref().map_or(false, |name| cookie.name() == name)
+        }) || (state.store.is_some() && session.is_empty())
         {
-            Outcome::Forward(())
+            Outcome::Forward(()) //in store mode, all clients without a session would share one token
+        } else if let Some(ref store) = state.store {
+            //synchronizer token mode, reuse the token stored for this session or issue a new one
+            let stored = store.get(&session);
+            let previous = stored
+                .as_ref()
+                .and_then(|(stored, expires)| CsrfCookie::from_stored(stored, *expires, &binding));
+            let next = match csrf_engine.renew(previous, *duration) {
+                Ok(next) => next,
+                Err(_) => return Outcome::Failure((Status::InternalServerError, ())),
+            };
+            store.set(&session, next.to_stored(&binding), next.expires());
+
+            let mut buf = [0; PAIR_SIZE];
+            match csrf_engine.generate_token_pair(&next, &binding, &mut buf) {
+                Ok((token, _)) => Outcome::Success(CsrfToken {
+                    value: match csrf_engine.mask_token(token) {
+                        Ok(token) => BASE64URL_NOPAD.encode(&token),
+                        Err(_) => return Outcome::Failure((Status::InternalServerError, ())),
+                    },
+                    engine: csrf_engine.clone(),
+                    tag: next.token().to_vec(),
+                    session: binding,
+                }),
+                Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+            }
         } else {
-            let mut token_value = cookies
+            let mut previous = cookies
                 .get(CSRF_COOKIE_NAME)
                 .and_then(|cookie| BASE64URL_NOPAD.decode(cookie.value().as_bytes()).ok());
-            let token_value = token_value.as_mut().and_then(|cookie| csrf_engine.parse_cookie(&mut *cookie).ok());
+            let previous = previous.as_mut().and_then(|cookie| csrf_engine.parse_cookie(&mut *cookie, &binding).ok());
+            let next = match csrf_engine.renew(previous, *duration) {
+                Ok(next) => next,
+                Err(_) => return Outcome::Failure((Status::InternalServerError, ())),
+            }; //reuse the value of the previous cookie, unless it must be rotated
 
-            let mut buf = [0; 192];
-            match csrf_engine.generate_token_pair(token_value, *duration, &mut buf) {
+            let mut buf = [0; PAIR_SIZE];
+            match csrf_engine.generate_token_pair(&next, &binding, &mut buf) {
                 Ok((token, cookie)) => {
                     let c =
                         Cookie::build(CSRF_COOKIE_NAME, BASE64URL_NOPAD.encode(cookie))
@@ -66,12 +142,20 @@ impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
                             .secure(true)
                             .same_site(SameSite::Strict)
                             .path("/")
-                            .max_age(Duration::seconds(*duration as i64))
+                            .max_age(Duration::seconds(next.time_left() as i64))
                             .finish();
 
+                    let token = match csrf_engine.mask_token(token) {
+                        Ok(token) => token,
+                        Err(_) => return Outcome::Failure((Status::InternalServerError, ())),
+                    };
+
                     cookies.add(c);
                     Outcome::Success(CsrfToken {
-                        value: BASE64URL_NOPAD.encode(token),
+                        value: BASE64URL_NOPAD.encode(&token),
+                        engine: csrf_engine.clone(),
+                        tag: next.token().to_vec(),
+                        session: binding,
                     })
                 }
                 Err(_) => Outcome::Failure((Status::InternalServerError, ())),
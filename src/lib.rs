@@ -1,5 +1,5 @@
 #![deny(missing_docs)]
-#![cfg_attr(feature = "cargo-clippy", deny(warnings))]
+#![cfg_attr(clippy, deny(warnings))]
 #![feature(decl_macro, test, proc_macro_hygiene)]
 //! # Rocket Csrf
 //!
@@ -9,6 +9,14 @@
 //!
 //! - Automatically protect all POST, PUT, DELETE and PATCH endpoints
 //! - Ability to define exceptions
+//! - Optionally bind tokens to your application session
+//! - Stateless double-submit cookies, or synchronizer tokens kept in a pluggable store
+//! - Single-use tokens for sensitive routes
+//! - Automatic insertion of tokens into forms, including in gzip and deflate encoded pages with the
+//!   `compression` feature
+//! - Streaming html rewriter, to which your own element handlers can be added
+//! - Framework independent [token issuance and verification](protection/index.html), to share
+//!   tokens with non-Rocket code
 //!
 //! ## Usage
 //!
@@ -43,6 +51,11 @@
 //! errors will simply be redirected to the route matching `/`
 //!
 extern crate data_encoding;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[macro_use]
+extern crate log;
+extern crate memchr;
 extern crate ring;
 extern crate serde;
 extern crate test;
@@ -53,15 +66,28 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket;
 
+mod charset;
+mod compression;
 mod csrf_fairing;
 mod csrf_proxy;
 mod csrf_token;
+mod no_insert;
 mod path;
+pub mod protection;
+mod rewriter;
 mod utils;
 mod crypto;
+mod token_store;
 
 pub use self::csrf_fairing::{CsrfFairing, CsrfFairingBuilder};
 pub use self::csrf_token::CsrfToken;
+pub use self::no_insert::NoCsrfInsert;
+pub use self::protection::Algorithm;
+pub use self::rewriter::{Element, ElementHandler, HtmlRewriter, Rewrite};
+pub use self::token_store::{MemoryReplayCache, MemoryTokenStore, ReplayCache, TokenStore};
+/// Header a handler may set on a response to prevent tokens from being inserted into it, as done by
+/// [NoCsrfInsert](struct.NoCsrfInsert.html). The header is removed before the response is sent.
+pub const NO_INSERT_HEADER: &str = "X-Csrf-No-Insert";
 const CSRF_COOKIE_NAME: &str = "csrf";
 const CSRF_FORM_FIELD: &str = "csrf-token";
 const CSRF_FORM_FIELD_MULTIPART: &[u8] = b"Content-Disposition: form-data; name=\"csrf-token\"";
@@ -70,7 +96,10 @@ const CSRF_FORM_FIELD_MULTIPART: &[u8] = b"Content-Disposition: form-data; name=
 #[cfg(test)]
 mod tests {
     use super::*;
+    use csrf_proxy::CsrfInserter;
+    use protection::CsrfProtection;
     use rocket::{http::Cookie, local::Client};
+    use std::io;
     use test::Bencher;
 
     #[bench]
@@ -122,6 +151,81 @@ mod tests {
         });
     }
 
+    // a page of about 1MiB, with many tags and a form in each section
+    fn large_page(form: bool) -> Vec<u8> {
+        let form = if form {
+            "<form method=post action=/comment><input name=text><button>Send</button></form>"
+        } else {
+            "<p>No comments.</p>"
+        };
+        let section = format!(
+            "<div class=\"post\"><h2>Title</h2><p>Some <em>content</em>, with a <a href=\"/link\">link</a>.</p>\
+<script>if (a < b) {{ f('</div>'); }}</script><!-- <form> -->{}</div>\n{}\n",
+            form,
+            "Text of the document...... ".repeat(20),
+        );
+        section.repeat(1024 * 1024 / section.len()).into_bytes()
+    }
+
+    fn bench_proxy(b: &mut Bencher, page: &[u8]) {
+        b.bytes = page.len() as u64;
+        b.iter(|| {
+            let mut proxy = HtmlRewriter::new(Box::new(page), CsrfInserter::new(b"abcd"));
+            io::copy(&mut proxy, &mut io::sink()).unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_proxy_throughput(b: &mut Bencher) {
+        bench_proxy(b, &large_page(true));
+    }
+
+    #[bench]
+    fn bench_proxy_throughput_no_form(b: &mut Bencher) {
+        bench_proxy(b, &large_page(false));
+    }
+
+    // issue a token pair, as done for each page served
+    fn bench_generate(b: &mut Bencher, protection: CsrfProtection) {
+        b.iter(|| protection.generate(b"session", 3600).unwrap());
+    }
+
+    // check a token pair, as done for each form submitted
+    fn bench_verify(b: &mut Bencher, protection: CsrfProtection) {
+        let pair = protection.generate(b"session", 3600).unwrap();
+        b.iter(|| protection.verify_encoded(pair.token(), pair.cookie(), b"session").unwrap());
+    }
+
+    #[bench]
+    fn bench_generate_tokens(b: &mut Bencher) {
+        bench_generate(b, CsrfProtection::from_key([0; 32]));
+    }
+
+    #[bench]
+    fn bench_generate_compact_tokens(b: &mut Bencher) {
+        bench_generate(b, CsrfProtection::from_key([0; 32]).with_compact_tokens(true));
+    }
+
+    #[bench]
+    fn bench_verify_tokens(b: &mut Bencher) {
+        bench_verify(b, CsrfProtection::from_key([0; 32]));
+    }
+
+    #[bench]
+    fn bench_verify_compact_tokens(b: &mut Bencher) {
+        bench_verify(b, CsrfProtection::from_key([0; 32]).with_compact_tokens(true));
+    }
+
+    #[bench]
+    fn bench_generate_signed_tokens(b: &mut Bencher) {
+        bench_generate(b, CsrfProtection::from_key([0; 32]).with_algorithm(Algorithm::HmacSha256));
+    }
+
+    #[bench]
+    fn bench_verify_signed_tokens(b: &mut Bencher) {
+        bench_verify(b, CsrfProtection::from_key([0; 32]).with_algorithm(Algorithm::HmacSha256));
+    }
+
     #[get("/")]
     fn index() -> ::rocket::response::content::Content<&'static str> {
         ::rocket::response::content::Content(
@@ -0,0 +1,36 @@
+use rocket::response::{self, Responder};
+use rocket::Request;
+
+use NO_INSERT_HEADER;
+
+/// Responder wrapper preventing tokens from being inserted into a response.
+///
+/// This is usefull for handlers serving html you don't control, such as user-generated pages or
+/// previews of emails, without having to list their paths in
+/// [`set_auto_insert_disable_prefix`](struct.CsrfFairingBuilder.html#method.set_auto_insert_disable_prefix).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # #![feature(proc_macro_hygiene, decl_macro)]
+/// # #[macro_use] extern crate rocket;
+/// # extern crate rocket_csrf;
+/// use rocket::response::content::Html;
+/// use rocket_csrf::NoCsrfInsert;
+///
+/// #[get("/preview")]
+/// fn preview() -> NoCsrfInsert<Html<String>> {
+///     NoCsrfInsert(Html("<form method=post></form>".to_owned()))
+/// }
+/// # fn main() {}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoCsrfInsert<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for NoCsrfInsert<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = self.0.respond_to(request)?;
+        response.set_raw_header(NO_INSERT_HEADER, "1"); //removed by the fairing
+        Ok(response)
+    }
+}
@@ -0,0 +1,389 @@
+//! Issuance and verification of tokens outside of Rocket.
+//!
+//! The fairing and the [`CsrfToken`](../struct.CsrfToken.html) guard are built on top of
+//! [`CsrfProtection`](struct.CsrfProtection.html), which is exposed here so tokens can also be
+//! issued and checked from a background worker, a websocket handler, or another service sharing
+//! the same secret. Nothing in this module depends on Rocket.
+//!
+//! Tokens and cookies are exchanged in the same encoded form as the fairing uses: the token is
+//! what is sent in forms and headers, the cookie what is stored in the `csrf` cookie. To
+//! interoperate with a fairing, use the secret given to
+//! [`set_secret`](../struct.CsrfFairingBuilder.html#method.set_secret), the same
+//! [`Algorithm`](enum.Algorithm.html) and format, and the session identifier returned by its
+//! session extractor, or an empty one if tokens are not bound to sessions. If a privilege
+//! extractor is set too, bind tokens to the result of [`bind_privilege`](fn.bind_privilege.html)
+//! instead.
+//!
+//! # Example
+//!
+//! ```rust
+//! # extern crate rocket_csrf;
+//! use rocket_csrf::protection::CsrfProtection;
+//!
+//! # fn main() {
+//! let protection = CsrfProtection::from_key([0; 32]); //use a truly secret key instead
+//! let pair = protection.generate(b"session id", 3600).unwrap();
+//!
+//! //later, when the token is sent back along with the cookie
+//! assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"session id").is_ok());
+//! assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"other session").is_err());
+//! # }
+//! ```
+
+use data_encoding::BASE64URL_NOPAD;
+
+use crypto;
+pub use crypto::{Algorithm, CsrfError};
+
+/// Issue and verify tokens and cookies with a secret key.
+#[derive(Clone)]
+pub struct CsrfProtection {
+    engine: crypto::CsrfProtection,
+}
+
+impl CsrfProtection {
+    /// Create a new `CsrfProtection` from a 32 bytes secret key, using the default algorithm.
+    pub fn from_key(key: [u8; 32]) -> Self {
+        CsrfProtection {
+            engine: crypto::CsrfProtection::from_key(key),
+        }
+    }
+
+    /// Set the algorithm tokens and cookies are sealed with. It must be the same as the one given
+    /// to [`set_algorithm`](../struct.CsrfFairingBuilder.html#method.set_algorithm) to
+    /// interoperate with a fairing.
+    pub fn with_algorithm(self, algorithm: Algorithm) -> Self {
+        CsrfProtection {
+            engine: self.engine.with_algorithm(algorithm),
+        }
+    }
+
+    /// Set if compact tokens and cookies should be issued and expected. It must be the same as
+    /// what is given to
+    /// [`set_compact_tokens`](../struct.CsrfFairingBuilder.html#method.set_compact_tokens) to
+    /// interoperate with a fairing.
+    pub fn with_compact_tokens(self, compact: bool) -> Self {
+        CsrfProtection {
+            engine: self.engine.with_compact(compact),
+        }
+    }
+
+    /// Set the maximum lifetime (in seconds) of token values, after which they are rejected and
+    /// rotated by [`refresh`](#method.refresh). It should be the same as the one given to
+    /// [`set_max_lifetime`](../struct.CsrfFairingBuilder.html#method.set_max_lifetime) to
+    /// interoperate with a fairing.
+    pub fn with_max_lifetime(self, max_lifetime: u64) -> Self {
+        CsrfProtection {
+            engine: self.engine.with_max_lifetime(Some(max_lifetime)),
+        }
+    }
+
+    /// Get the algorithm tokens and cookies are sealed with.
+    pub fn algorithm(&self) -> Algorithm {
+        self.engine.algorithm()
+    }
+
+    /// Generate a new token and cookie pair with a fresh random value, bound to `session` and
+    /// valid for `ttl_seconds`.
+    pub fn generate(&self, session: &[u8], ttl_seconds: u64) -> Result<TokenPair, CsrfError> {
+        self.generate_pair(None, session, ttl_seconds)
+    }
+
+    /// Generate a new token and cookie pair carrying the same value as `cookie`, with a new
+    /// expiration date. Tokens issued before remain valid with the new cookie, as done when a page
+    /// is served to a client that already has a cookie. If `cookie` expired or reached its maximum
+    /// lifetime, a pair with a fresh value is generated instead.
+    pub fn refresh(&self, cookie: &Cookie, session: &[u8], ttl_seconds: u64) -> Result<TokenPair, CsrfError> {
+        self.generate_pair(Some(cookie.to_crypto()), session, ttl_seconds)
+    }
+
+    fn generate_pair(
+        &self,
+        previous: Option<crypto::CsrfCookie>,
+        session: &[u8],
+        ttl_seconds: u64,
+    ) -> Result<TokenPair, CsrfError> {
+        let next = self.engine.renew(previous, ttl_seconds)?;
+        let mut buf = [0; crypto::PAIR_SIZE];
+        let (token, cookie) = self.engine.generate_token_pair(&next, session, &mut buf)?;
+        let token = self.engine.mask_token(token)?;
+        Ok(TokenPair {
+            token: BASE64URL_NOPAD.encode(&token),
+            cookie: BASE64URL_NOPAD.encode(cookie),
+            expires: next.expires(),
+        })
+    }
+
+    /// Decode and authenticate a token, as sent in a form or header.
+    pub fn parse_token(&self, token: &str, session: &[u8]) -> Result<Token, CsrfError> {
+        let mut token = BASE64URL_NOPAD
+            .decode(token.trim().as_bytes())
+            .map_err(|_| CsrfError::ValidationError)?;
+        let token = self.engine.parse_token(&mut token, session)?;
+        Ok(Token {
+            id: token.id().to_vec(),
+            value: token.value().to_vec(),
+            issued: token.issued(),
+        })
+    }
+
+    /// Decode and authenticate a cookie. The cookie may have expired already.
+    pub fn parse_cookie(&self, cookie: &str, session: &[u8]) -> Result<Cookie, CsrfError> {
+        let mut cookie = BASE64URL_NOPAD
+            .decode(cookie.as_bytes())
+            .map_err(|_| CsrfError::ValidationError)?;
+        let cookie = self.engine.parse_cookie(&mut cookie, session)?;
+        Ok(Cookie {
+            value: cookie.token().to_vec(),
+            expires: cookie.expires(),
+            issued: cookie.issued(),
+        })
+    }
+
+    /// Check a token against a cookie. They must have been issued together, or by
+    /// [`refresh`](#method.refresh) from each other, and the cookie must neither be expired nor
+    /// past its maximum lifetime.
+    pub fn verify(&self, token: &Token, cookie: &Cookie) -> Result<(), CsrfError> {
+        let parsed = cookie.to_crypto();
+        if !self.engine.is_live(&parsed) {
+            Err(CsrfError::Expired)
+        } else if self.engine.verify_token_pair(&crypto::CsrfToken::new(&token.id, &token.value, token.issued), &parsed) {
+            Ok(())
+        } else {
+            Err(CsrfError::ValidationError)
+        }
+    }
+
+    /// Decode, authenticate and check a token against a cookie, in their encoded forms.
+    pub fn verify_encoded(&self, token: &str, cookie: &str, session: &[u8]) -> Result<(), CsrfError> {
+        self.verify(&self.parse_token(token, session)?, &self.parse_cookie(cookie, session)?)
+    }
+}
+
+/// Bind tokens to a privilege level along with a session, as done by the fairing when a
+/// [privilege extractor](../struct.CsrfFairingBuilder.html#method.set_privilege_extractor) is set.
+/// The result is to be used in place of the session identifier.
+pub fn bind_privilege(session: &[u8], privilege: &[u8]) -> Vec<u8> {
+    let mut binding = Vec::with_capacity(8 + session.len() + privilege.len());
+    binding.extend_from_slice(&(session.len() as u64).to_be_bytes());
+    binding.extend_from_slice(session);
+    binding.extend_from_slice(privilege);
+    binding
+}
+
+/// A token and cookie issued together, in their encoded forms.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenPair {
+    token: String,
+    cookie: String,
+    expires: u64,
+}
+
+impl TokenPair {
+    /// The token, to send in a form or header. Tokens are masked with random bytes, so a new one is
+    /// different each time even when it carries the same value.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The cookie, to keep on the client side.
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    /// Expiration date of the cookie, in seconds since Unix epoch.
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+}
+
+/// A decoded and authenticated token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    id: Vec<u8>,
+    value: Vec<u8>,
+    issued: u64,
+}
+
+impl Token {
+    /// Unique identifier of this token, usable to detect it being replayed.
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Date at which this token was issued, in seconds since Unix epoch. Tokens issued by
+    /// [`refresh`](struct.CsrfProtection.html#method.refresh) carry the date they were refreshed
+    /// at, even if their value is older.
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
+}
+
+/// A decoded and authenticated cookie.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    value: Vec<u8>,
+    expires: u64,
+    issued: u64,
+}
+
+impl Cookie {
+    fn to_crypto(&self) -> crypto::CsrfCookie {
+        crypto::CsrfCookie::new(&self.value, self.expires, self.issued)
+    }
+
+    /// Expiration date of this cookie, in seconds since Unix epoch.
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+
+    /// Date at which the value of this cookie was first issued, in seconds since Unix epoch.
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
+
+    /// Seconds left before this cookie expires, zero if it already did.
+    pub fn time_left(&self) -> u64 {
+        self.to_crypto().time_left()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::BASE64URL_NOPAD;
+    use protection::{Algorithm, CsrfError, CsrfProtection};
+
+    #[test]
+    fn test_generate_verify() {
+        let protection = CsrfProtection::from_key([1; 32]);
+        let pair = protection.generate(b"session", 60).unwrap();
+        assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"session").is_ok());
+        assert_eq!(protection.parse_cookie(pair.cookie(), b"session").unwrap().expires(), pair.expires());
+
+        //bound to the session and the key
+        assert_eq!(
+            protection.verify_encoded(pair.token(), pair.cookie(), b"other"),
+            Err(CsrfError::ValidationError)
+        );
+        let other = CsrfProtection::from_key([2; 32]);
+        assert!(other.verify_encoded(pair.token(), pair.cookie(), b"session").is_err());
+
+        //tokens only match their own cookie, or a refreshed one
+        let second = protection.generate(b"session", 60).unwrap();
+        assert_eq!(
+            protection.verify_encoded(pair.token(), second.cookie(), b"session"),
+            Err(CsrfError::ValidationError)
+        );
+        let cookie = protection.parse_cookie(pair.cookie(), b"session").unwrap();
+        let refreshed = protection.refresh(&cookie, b"session", 60).unwrap();
+        assert_ne!(refreshed.token(), pair.token());
+        assert!(protection.verify_encoded(pair.token(), refreshed.cookie(), b"session").is_ok());
+        assert!(protection.verify_encoded(refreshed.token(), pair.cookie(), b"session").is_ok());
+
+        //expired cookies are rejected
+        let expired = protection.generate(b"session", 0).unwrap();
+        assert_eq!(
+            protection.verify_encoded(expired.token(), expired.cookie(), b"session"),
+            Err(CsrfError::Expired)
+        );
+
+        //garbage is rejected
+        assert!(protection.parse_token("not a token", b"session").is_err());
+        assert!(protection.parse_cookie("", b"session").is_err());
+    }
+
+    const ALGORITHMS: &[Algorithm] = &[Algorithm::ChaCha20Poly1305, Algorithm::Aes256Gcm, Algorithm::HmacSha256];
+
+    #[test]
+    fn test_algorithm() {
+        assert_eq!(CsrfProtection::from_key([1; 32]).algorithm(), Algorithm::ChaCha20Poly1305);
+        for &algorithm in ALGORITHMS {
+            let protection = CsrfProtection::from_key([1; 32]).with_algorithm(algorithm);
+            let pair = protection.generate(b"session", 60).unwrap();
+            assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"session").is_ok());
+            assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"other").is_err());
+            let other = protection.generate(b"session", 60).unwrap();
+            assert!(protection.verify_encoded(pair.token(), other.cookie(), b"session").is_err());
+            let other_key = CsrfProtection::from_key([2; 32]).with_algorithm(algorithm);
+            assert!(other_key.verify_encoded(pair.token(), pair.cookie(), b"session").is_err());
+
+            //the algorithm is recorded, a pair sealed with one isn't accepted by the others
+            for &other in ALGORITHMS.iter().filter(|&&other| other != algorithm) {
+                let other = CsrfProtection::from_key([1; 32]).with_algorithm(other);
+                assert_eq!(
+                    other.verify_encoded(pair.token(), pair.cookie(), b"session"),
+                    Err(CsrfError::ValidationError)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_signed_tokens() {
+        let protection = CsrfProtection::from_key([1; 32]).with_algorithm(Algorithm::HmacSha256);
+        let pair = protection.generate(b"session", 60).unwrap();
+        assert_eq!((pair.token().len(), pair.cookie().len()), (270, 146));
+        let compact = protection.clone().with_compact_tokens(true);
+        let pair = compact.generate(b"session", 60).unwrap();
+        assert_eq!((pair.token().len(), pair.cookie().len()), (142, 82)); //still masked
+        assert!(compact.verify_encoded(pair.token(), pair.cookie(), b"session").is_ok());
+
+        //tampering with the value, which is in clear, is detected
+        let mut cookie = BASE64URL_NOPAD.decode(pair.cookie().as_bytes()).unwrap();
+        cookie[30] ^= 1;
+        assert!(compact.parse_cookie(&BASE64URL_NOPAD.encode(&cookie), b"session").is_err());
+    }
+
+    #[test]
+    fn test_compact_tokens() {
+        let standard = CsrfProtection::from_key([1; 32]);
+        let compact = CsrfProtection::from_key([1; 32]).with_compact_tokens(true);
+        let pair = standard.generate(b"session", 60).unwrap();
+        assert_eq!((pair.token().len(), pair.cookie().len()), (270, 146));
+        let pair = compact.generate(b"session", 60).unwrap();
+        assert_eq!((pair.token().len(), pair.cookie().len()), (71, 82));
+        assert!(compact.verify_encoded(pair.token(), pair.cookie(), b"session").is_ok());
+        let cookie = compact.parse_cookie(pair.cookie(), b"session").unwrap();
+        let refreshed = compact.refresh(&cookie, b"session", 60).unwrap();
+        assert_ne!(refreshed.token(), pair.token());
+        assert!(compact.verify_encoded(pair.token(), refreshed.cookie(), b"session").is_ok());
+
+        //the format is recorded, compact tokens are only accepted in compact mode
+        assert!(standard.parse_token(pair.token(), b"session").is_err());
+        assert!(standard.parse_cookie(pair.cookie(), b"session").is_err());
+        let pair = standard.generate(b"session", 60).unwrap();
+        assert!(compact.parse_token(pair.token(), b"session").is_err());
+        assert!(compact.parse_cookie(pair.cookie(), b"session").is_err());
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let protection = CsrfProtection::from_key([1; 32]).with_max_lifetime(100);
+        let pair = protection.generate(b"session", 3600).unwrap();
+        let mut cookie = protection.parse_cookie(pair.cookie(), b"session").unwrap();
+        assert_eq!(cookie.expires(), cookie.issued() + 100); //capped by the lifetime
+        assert!(protection.verify_encoded(pair.token(), pair.cookie(), b"session").is_ok());
+
+        //a value issued too long ago is rejected, and rotated when refreshed
+        cookie.issued -= 100;
+        let token = protection.parse_token(pair.token(), b"session").unwrap();
+        assert_eq!(protection.verify(&token, &cookie), Err(CsrfError::Expired));
+        let refreshed = protection.refresh(&cookie, b"session", 3600).unwrap();
+        assert_eq!(
+            protection.verify_encoded(pair.token(), refreshed.cookie(), b"session"),
+            Err(CsrfError::ValidationError)
+        );
+        assert!(protection.verify_encoded(refreshed.token(), refreshed.cookie(), b"session").is_ok());
+    }
+
+    #[test]
+    fn test_token_id() {
+        let protection = CsrfProtection::from_key([1; 32]);
+        let pair = protection.generate(b"", 60).unwrap();
+        let token = protection.parse_token(pair.token(), b"").unwrap();
+        assert_eq!(protection.parse_token(pair.token(), b"").unwrap().id(), token.id());
+        let cookie = protection.parse_cookie(pair.cookie(), b"").unwrap();
+        let refreshed = protection.refresh(&cookie, b"", 60).unwrap();
+        assert_ne!(protection.parse_token(refreshed.token(), b"").unwrap().id(), token.id());
+    }
+}
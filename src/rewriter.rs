@@ -0,0 +1,929 @@
+use memchr::{memchr, memmem};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Error, Read};
+use std::mem;
+
+// where a part of the output comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    Input,    //input passed through
+    Inserted, //content inserted by handlers
+}
+
+// read at least this much of the underlying reader at once
+const MIN_READ: usize = 4096;
+
+/// Input of a rewriter, and output made of it. Input is kept in place until it's read, and only
+/// inserted content is copied, to a ring buffer, so passing data through copy it only once
+#[derive(Debug)]
+struct Buffer {
+    input: Vec<u8>, //input[start..parsed] is output not yet read, input[parsed..end] is not parsed
+    start: usize,
+    parsed: usize,
+    end: usize,
+    inserted: VecDeque<u8>,
+    segments: VecDeque<(Source, usize)>, //origin and length of the parts of the output, in order
+    len: usize,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            input: Vec::new(),
+            start: 0,
+            parsed: 0,
+            end: 0,
+            inserted: VecDeque::new(),
+            segments: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    // read more input, return false at the end of the underlying reader
+    fn fill(&mut self, underlying: &mut dyn Read) -> Result<bool, Error> {
+        if self.input.len() - self.end < MIN_READ {
+            //reuse the space of what was already read, and grow only for markups which don't fit
+            self.input.copy_within(self.start..self.end, 0);
+            self.parsed -= self.start;
+            self.end -= self.start;
+            self.start = 0;
+            if self.input.len() - self.end < MIN_READ {
+                let len = cmp::max(self.input.len() * 2, 2 * MIN_READ);
+                self.input.resize(len, 0);
+            }
+        }
+        let len = underlying.read(&mut self.input[self.end..])?;
+        self.end += len;
+        Ok(len > 0)
+    }
+
+    // input from pos to the end of what was read
+    fn input_from(&self, pos: usize) -> &[u8] {
+        &self.input[pos..self.end]
+    }
+
+    // pass input up to pos to the output
+    fn pass_until(&mut self, pos: usize) {
+        let len = pos - self.parsed;
+        self.parsed = pos;
+        self.push_segment(Source::Input, len);
+    }
+
+    fn push_back(&mut self, value: &[u8]) {
+        self.inserted.extend(value);
+        self.push_segment(Source::Inserted, value.len());
+    }
+
+    fn push_segment(&mut self, source: Source, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.len += len;
+        match self.segments.back_mut() {
+            Some((last, last_len)) if *last == source => *last_len += len,
+            _ => self.segments.push_back((source, len)),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while buf.len() > read && !self.is_empty() {
+            let (source, part_len) = self.segments[0];
+            let to_copy = cmp::min(part_len, buf.len() - read);
+            let dest = &mut buf[read..read + to_copy];
+            match source {
+                Source::Input => {
+                    dest.copy_from_slice(&self.input[self.start..self.start + to_copy]);
+                    self.start += to_copy;
+                }
+                Source::Inserted => {
+                    let (front, back) = self.inserted.as_slices();
+                    let from_front = cmp::min(front.len(), to_copy);
+                    dest[..from_front].copy_from_slice(&front[..from_front]);
+                    dest[from_front..].copy_from_slice(&back[..to_copy - from_front]);
+                    self.inserted.drain(..to_copy);
+                }
+            }
+            read += to_copy;
+            self.len -= to_copy;
+            if part_len == to_copy {
+                self.segments.pop_front();
+            } else {
+                self.segments[0].1 -= to_copy;
+            }
+        }
+        read
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Markup<'a> {
+    Tag(Element<'a>),
+    Comment, //comments, doctype and other markup declarations
+    Text,    //a '<' which does not open any markup
+}
+
+/// Start or end tag of an element, given to [ElementHandler](trait.ElementHandler.html)s.
+///
+/// Names are lowercased. Attribute values are given as found in the document, without decoding
+/// character references.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Element<'a> {
+    name: Cow<'a, [u8]>, //lowercased name, borrowed from the document when it already is
+    end: bool,           //true for end tags
+    self_closing: bool,  //true for tags ending with "/>"
+    attributes: Vec<(Cow<'a, [u8]>, &'a [u8])>,
+}
+
+impl<'a> Element<'a> {
+    /// Get the name of the element, in lowercase.
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Check if this is an end tag, like `</form>`.
+    pub fn is_end_tag(&self) -> bool {
+        self.end
+    }
+
+    /// Check if this tag ends with `/>`.
+    pub fn is_self_closing(&self) -> bool {
+        self.self_closing
+    }
+
+    /// Get the value of an attribute, given its lowercase name. If an attribute is repeated, only
+    /// its first occurence counts, as in browsers.
+    pub fn attribute(&self, name: &[u8]) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n[..] == *name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Iterate over the names and values of attributes.
+    pub fn attributes(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.attributes.iter().map(|(name, value)| (&name[..], *value))
+    }
+}
+
+/// Changes an [ElementHandler](trait.ElementHandler.html) asks for around a tag.
+#[derive(Debug, Default)]
+pub struct Rewrite {
+    before: Vec<u8>,
+    after: Vec<u8>,
+    attributes: Vec<u8>,
+}
+
+impl Rewrite {
+    /// Insert content right before the tag.
+    pub fn insert_before(&mut self, content: &[u8]) {
+        self.before.extend_from_slice(content);
+    }
+
+    /// Insert content right after the tag.
+    pub fn insert_after(&mut self, content: &[u8]) {
+        self.after.extend_from_slice(content);
+    }
+
+    /// Add an attribute to the tag. The value is escaped.
+    pub fn add_attribute(&mut self, name: &str, value: &str) {
+        self.attributes.push(b' ');
+        self.attributes.extend_from_slice(name.as_bytes());
+        self.attributes.extend_from_slice(b"=\"");
+        for &c in value.as_bytes() {
+            match c {
+                b'&' => self.attributes.extend_from_slice(b"&amp;"),
+                b'"' => self.attributes.extend_from_slice(b"&quot;"),
+                c => self.attributes.push(c),
+            }
+        }
+        self.attributes.push(b'"');
+    }
+
+    fn is_empty(&self) -> bool {
+        self.before.is_empty() && self.after.is_empty() && self.attributes.is_empty()
+    }
+
+    // forget previous changes, keeping allocated memory
+    fn clear(&mut self) {
+        self.before.clear();
+        self.after.clear();
+        self.attributes.clear();
+    }
+}
+
+/// Handler of the elements of a document rewritten by an [HtmlRewriter](struct.HtmlRewriter.html).
+///
+/// Several handlers can be combined in a tuple or a `Vec`, each of them seeing the original tags.
+///
+/// # Example
+///
+/// ```rust
+/// use rocket_csrf::{Element, ElementHandler, HtmlRewriter, Rewrite};
+/// use std::io::{Cursor, Read};
+///
+/// struct ScriptNonce(String);
+///
+/// impl ElementHandler for ScriptNonce {
+///     fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+///         if element.name() == b"script" && !element.is_end_tag() {
+///             rewrite.add_attribute("nonce", &self.0);
+///         }
+///     }
+/// }
+///
+/// let page = "<script src='app.js'></script>";
+/// let mut rewriter = HtmlRewriter::new(Box::new(Cursor::new(page)), ScriptNonce("r4nd".to_owned()));
+/// let mut res = String::new();
+/// rewriter.read_to_string(&mut res).unwrap();
+/// assert_eq!(res, "<script src='app.js' nonce=\"r4nd\"></script>");
+/// ```
+pub trait ElementHandler {
+    /// Handle a start or end tag. Tags inside comments, raw text elements such as `<script>` or
+    /// `<textarea>`, and templates are not given to handlers.
+    fn element(&mut self, element: &Element, rewrite: &mut Rewrite);
+}
+
+impl<H: ElementHandler + ?Sized> ElementHandler for Box<H> {
+    fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+        (**self).element(element, rewrite)
+    }
+}
+
+impl<H: ElementHandler> ElementHandler for Option<H> {
+    fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+        if let Some(handler) = self {
+            handler.element(element, rewrite)
+        }
+    }
+}
+
+impl<H: ElementHandler> ElementHandler for Vec<H> {
+    fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+        for handler in self {
+            handler.element(element, rewrite)
+        }
+    }
+}
+
+impl<A: ElementHandler, B: ElementHandler> ElementHandler for (A, B) {
+    fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+        self.0.element(element, rewrite);
+        self.1.element(element, rewrite);
+    }
+}
+
+/// Streaming rewriter of html documents.
+///
+/// The `HtmlRewriter` type reads a document from an underlying reader, and gives each of its tags
+/// to an [ElementHandler](trait.ElementHandler.html) which may insert content around it, without
+/// holding the whole document in memory. This is what insert tokens into forms, but it can be used
+/// for other rewrites, see
+/// [`add_element_handler`](struct.CsrfFairingBuilder.html#method.add_element_handler).
+pub struct HtmlRewriter<'a, H> {
+    underlying: Box<dyn Read + 'a>, //the underlying Reader from which we get data
+    handler: H,
+    buf: Buffer,
+    max_buffer: usize,               //maximum length of input waiting to be parsed
+    rewrite: Rewrite,                //reused for every tag
+    raw_text: Option<&'static [u8]>, //name of the raw text element (script, style...) we are in
+    skip: Option<Skip>,              //markup too long to be buffered we are in
+    template_depth: usize,           //number of template elements we are in
+    xml: bool,                       //whether the document use the xml syntax of html
+    eof: bool,
+}
+
+// elements whose content is not parsed as markup
+const RAW_TEXT_ELEMENTS: &[&[u8]] = &[
+    b"script", b"style", b"textarea", b"title", b"xmp", b"iframe", b"noembed", b"noframes", b"plaintext",
+];
+
+// default maximum length of input waiting to be parsed
+const DEFAULT_MAX_BUFFER: usize = 64 * 1024;
+
+impl<'a, H: ElementHandler> HtmlRewriter<'a, H> {
+    /// Create a rewriter of the document read from `underlying`.
+    pub fn new(underlying: Box<dyn Read + 'a>, handler: H) -> Self {
+        HtmlRewriter {
+            underlying,
+            handler,
+            buf: Buffer::new(),
+            max_buffer: DEFAULT_MAX_BUFFER,
+            rewrite: Rewrite::default(),
+            raw_text: None,
+            skip: None,
+            template_depth: 0,
+            xml: false,
+            eof: false,
+        }
+    }
+
+    /// Parse the document as xhtml, in which any element can be self-closing.
+    pub fn with_xml_syntax(mut self, xml: bool) -> Self {
+        self.xml = xml;
+        self
+    }
+
+    /// Set the maximum number of bytes buffered while waiting for the end of a markup, 64Kio by
+    /// default. Longer tags and comments are sent unmodified, without being given to the handler,
+    /// so memory stays bounded whatever the document.
+    pub fn with_max_buffer(mut self, max_buffer: usize) -> Self {
+        self.max_buffer = max_buffer;
+        self
+    }
+
+    /// Get the handler of this rewriter.
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Get the handler of this rewriter, mutably.
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    // parse input, passing what can be sent to the output along with the rewrites of handlers
+    fn parse(&mut self) {
+        let pos = self.parse_from(self.buf.parsed);
+        self.buf.pass_until(pos);
+    }
+
+    // parse from pos, return up to where input can be sent
+    fn parse_from(&mut self, mut pos: usize) -> usize {
+        loop {
+            if let Some(mut skip) = self.skip.take() {
+                match skip.scan(self.buf.input_from(pos)) {
+                    Ok(len) => {
+                        pos += len;
+                        if let Skip::Tag(tag) = skip {
+                            self.after_tag(tag.name, tag.end, tag.self_closing);
+                        }
+                    }
+                    Err(_) if self.eof => return self.buf.end,
+                    Err(len) => {
+                        self.skip = Some(skip);
+                        return pos + len; //wait for more data
+                    }
+                }
+            }
+            if let Some(element) = self.raw_text.take() {
+                match find_end_tag(self.buf.input_from(pos), element) {
+                    Some(Some(end_pos)) => pos += end_pos,
+                    Some(None) => {
+                        self.raw_text = Some(element);
+                        return self.buf.end;
+                    }
+                    None if self.eof => return self.buf.end,
+                    None => {
+                        let safe = self.buf.end.saturating_sub(element.len() + 2); //"</" and the name
+                        self.raw_text = Some(element);
+                        return cmp::max(pos, safe); //wait for more data
+                    }
+                }
+            }
+            match memchr(b'<', self.buf.input_from(pos)) {
+                Some(tag_pos) => pos += tag_pos,
+                None => return self.buf.end,
+            }
+            let (len, self_closing, end, name) = match parse_markup(&self.buf.input[pos..self.buf.end]) {
+                Some((Markup::Tag(element), len)) => {
+                    if self.template_depth == 0 {
+                        //template content is not part of the document
+                        self.rewrite.clear();
+                        self.handler.element(&element, &mut self.rewrite);
+                    }
+                    (len, element.self_closing, element.end, special_element(&element.name))
+                }
+                Some((_, len)) => {
+                    pos += len;
+                    continue;
+                }
+                None if self.eof => return self.buf.end, //unterminated markup
+                None => return pos,                      //wait for more data
+            };
+            if self.template_depth == 0 && !self.rewrite.is_empty() {
+                let attributes_end = if self_closing { pos + len - 2 } else { pos + len - 1 }; //before "/>" or ">"
+                self.buf.pass_until(pos);
+                self.buf.push_back(&self.rewrite.before);
+                self.buf.pass_until(attributes_end);
+                self.buf.push_back(&self.rewrite.attributes);
+                self.buf.pass_until(pos + len);
+                self.buf.push_back(&self.rewrite.after);
+            }
+            pos += len;
+            self.after_tag(name, end, self_closing);
+        }
+    }
+
+    // enter or leave the raw text element or template a tag starts or ends
+    fn after_tag(&mut self, name: Option<&'static [u8]>, end: bool, self_closing: bool) {
+        if self.xml && self_closing {
+            //empty element, like <script src="..."/>
+        } else if name == Some(b"template") {
+            if end {
+                self.template_depth = self.template_depth.saturating_sub(1);
+            } else {
+                self.template_depth += 1;
+            }
+        } else if !end {
+            self.raw_text = name;
+        }
+    }
+
+    // stop buffering a markup too long, it will be sent as is
+    fn skip_markup(&mut self) {
+        if self.skip.is_none() && self.raw_text.is_none() {
+            self.skip = Skip::new(self.buf.input_from(self.buf.parsed));
+        }
+    }
+}
+
+// get the name of a tag among those starting raw text or templates
+fn special_element(name: &[u8]) -> Option<&'static [u8]> {
+    RAW_TEXT_ELEMENTS
+        .iter()
+        .chain(&[&b"template"[..]])
+        .find(|special| special.eq_ignore_ascii_case(name))
+        .cloned()
+}
+
+// markup too long to be buffered, sent unmodified up to its end
+#[derive(Debug)]
+enum Skip {
+    Until(&'static [u8]), //a comment or another markup declaration, ending with this
+    Tag(SkippedTag),
+}
+
+#[derive(Debug)]
+struct SkippedTag {
+    start: usize, //where its name starts, until the first scan
+    state: TagState,
+    name: Option<&'static [u8]>, //name if the tag starts or ends a raw text element or template
+    end: bool,
+    self_closing: bool,
+}
+
+// state of the tokenizer of a tag, as followed by parse_tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TagState {
+    Name,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeValue,
+    Quoted(u8),
+    Unquoted,
+}
+
+impl Skip {
+    // start skipping the markup at the start of buf, None if more data is needed to know its kind
+    fn new(buf: &[u8]) -> Option<Self> {
+        let tag = |start: usize, end: bool| {
+            let name = buf[start..]
+                .iter()
+                .position(|&c| c.is_ascii_whitespace() || c == b'/' || c == b'>')
+                .and_then(|name_end| special_element(&buf[start..start + name_end]));
+            Skip::Tag(SkippedTag {
+                start,
+                state: TagState::Name,
+                name,
+                end,
+                self_closing: false,
+            })
+        };
+        match *buf.get(1)? {
+            b'!' if b"<!--".starts_with(&buf[..cmp::min(buf.len(), 4)]) => buf.get(3).map(|_| Skip::Until(b"-->")),
+            b'!' if b"<![CDATA[".starts_with(&buf[..cmp::min(buf.len(), 9)]) => {
+                buf.get(8).map(|_| Skip::Until(b"]]>"))
+            }
+            b'!' | b'?' => Some(Skip::Until(b">")),
+            b'/' => match *buf.get(2)? {
+                c if c.is_ascii_alphabetic() => Some(tag(2, true)),
+                _ => Some(Skip::Until(b">")),
+            },
+            c if c.is_ascii_alphabetic() => Some(tag(1, false)),
+            _ => None,
+        }
+    }
+
+    // search the end of the markup in buf, return the length up to and including it, or an error
+    // with the length which can be sent if it's not there
+    fn scan(&mut self, buf: &[u8]) -> Result<usize, usize> {
+        use self::TagState::*;
+
+        let tag = match self {
+            Skip::Until(pattern) => {
+                return match memmem::find(buf, pattern) {
+                    Some(pos) => Ok(pos + pattern.len()),
+                    None => Err(buf.len().saturating_sub(pattern.len() - 1)), //it may start at the end
+                }
+            }
+            Skip::Tag(tag) => tag,
+        };
+        let start = mem::replace(&mut tag.start, 0);
+        for (pos, &c) in buf.iter().enumerate().skip(start) {
+            let space = c.is_ascii_whitespace();
+            let state = match tag.state {
+                Quoted(quote) if c == quote => BeforeAttributeName,
+                Quoted(quote) => Quoted(quote),
+                _ if c == b'>' => return Ok(pos + 1),
+                Name | BeforeAttributeName | AttributeName | AfterAttributeName if c == b'/' => BeforeAttributeName,
+                Name | BeforeAttributeName | Unquoted if space => BeforeAttributeName,
+                Name => Name,
+                AttributeName | AfterAttributeName if space => AfterAttributeName,
+                AttributeName | AfterAttributeName if c == b'=' => BeforeValue,
+                BeforeAttributeName | AttributeName | AfterAttributeName => AttributeName, //a '=' may start a name
+                BeforeValue if space => BeforeValue,
+                BeforeValue if c == b'"' || c == b'\'' => Quoted(c),
+                BeforeValue | Unquoted => Unquoted,
+            };
+            tag.self_closing = c == b'/' && state == BeforeAttributeName;
+            tag.state = state;
+        }
+        Err(buf.len())
+    }
+}
+
+// search the end tag of a raw text element, return its position, Some(None) if the element never
+// ends, or None if more data is needed to know
+fn find_end_tag(buf: &[u8], name: &[u8]) -> Option<Option<usize>> {
+    if name == b"plaintext" {
+        return Some(None);
+    }
+    let mut pos = 0;
+    while let Some(tag_pos) = memmem::find(&buf[pos..], b"</") {
+        pos += tag_pos;
+        let after_name = pos + 2 + name.len();
+        if buf.len() <= after_name {
+            return None;
+        }
+        let delimited = buf[after_name].is_ascii_whitespace() || buf[after_name] == b'/' || buf[after_name] == b'>';
+        if buf[pos + 2..after_name].eq_ignore_ascii_case(name) && delimited {
+            return Some(Some(pos));
+        }
+        pos += 2;
+    }
+    None
+}
+
+// parse markup starting with '<', following the HTML tokenizer. Return the kind of markup found
+// and its length, or None if the buffer ends before the markup does
+fn parse_markup(buf: &[u8]) -> Option<(Markup, usize)> {
+    let until = |buf: &[u8], from: usize, pattern: &[u8]| {
+        memmem::find(buf.get(from..)?, pattern).map(|pos| from + pos + pattern.len())
+    };
+    match *buf.get(1)? {
+        b'!' => {
+            if b"<!--".starts_with(&buf[..cmp::min(buf.len(), 4)]) {
+                if buf.len() < 4 {
+                    return None;
+                }
+                until(buf, 2, b"-->").map(|len| (Markup::Comment, len))
+            } else if b"<![CDATA[".starts_with(&buf[..cmp::min(buf.len(), 9)]) {
+                if buf.len() < 9 {
+                    return None;
+                }
+                //only a section in xhtml or foreign content, but skipping it is harmless anyway
+                until(buf, 9, b"]]>").map(|len| (Markup::Comment, len))
+            } else {
+                until(buf, 2, b">").map(|len| (Markup::Comment, len)) //doctype or bogus comment
+            }
+        }
+        b'?' => until(buf, 2, b">").map(|len| (Markup::Comment, len)),
+        b'/' => match *buf.get(2)? {
+            c if c.is_ascii_alphabetic() => parse_tag(buf, 2, true),
+            b'>' => Some((Markup::Comment, 3)), //"</>" is ignored
+            _ => until(buf, 2, b">").map(|len| (Markup::Comment, len)),
+        },
+        c if c.is_ascii_alphabetic() => parse_tag(buf, 1, false),
+        _ => Some((Markup::Text, 1)),
+    }
+}
+
+// lowercase a name, without copying it if it already is
+fn lowercase(name: &[u8]) -> Cow<[u8]> {
+    if name.iter().any(u8::is_ascii_uppercase) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+// parse a tag whose name start at `start`, up to and including its closing '>'
+fn parse_tag(buf: &[u8], start: usize, end: bool) -> Option<(Markup, usize)> {
+    fn is_space(c: u8) -> bool {
+        c.is_ascii_whitespace()
+    }
+
+    let mut pos = start;
+    let name_end = pos + buf[pos..].iter().position(|&c| is_space(c) || c == b'/' || c == b'>')?;
+    let name = lowercase(&buf[pos..name_end]);
+    pos = name_end;
+
+    let mut attributes = Vec::new();
+    let mut self_closing = false;
+    loop {
+        //before attribute name
+        while is_space(*buf.get(pos)?) || buf[pos] == b'/' {
+            self_closing = buf[pos] == b'/';
+            pos += 1;
+        }
+        if buf[pos] == b'>' {
+            break;
+        }
+        self_closing = false;
+        let name_start = pos;
+        pos += 1; //a '=' here is part of the name
+        pos += buf[pos..].iter().position(|&c| is_space(c) || c == b'/' || c == b'>' || c == b'=')?;
+        let attr_name = lowercase(&buf[name_start..pos]);
+        //after attribute name
+        while is_space(*buf.get(pos)?) {
+            pos += 1;
+        }
+        let value = if buf[pos] == b'=' {
+            pos += 1;
+            while is_space(*buf.get(pos)?) {
+                pos += 1;
+            }
+            match buf[pos] {
+                quote @ b'"' | quote @ b'\'' => {
+                    let value_start = pos + 1;
+                    pos = value_start + memchr(quote, &buf[value_start..])?;
+                    let value = &buf[value_start..pos];
+                    pos += 1;
+                    value
+                }
+                b'>' => &[], //missing value
+                _ => {
+                    let value_start = pos;
+                    pos += buf[pos..].iter().position(|&c| is_space(c) || c == b'>')?;
+                    &buf[value_start..pos]
+                }
+            }
+        } else {
+            &[]
+        };
+        if !attributes.iter().any(|(n, _)| n == &attr_name) {
+            attributes.push((attr_name, value)); //first occurence win, as in browsers
+        }
+    }
+    Some((
+        Markup::Tag(Element {
+            name,
+            end,
+            self_closing,
+            attributes,
+        }),
+        pos + 1,
+    ))
+}
+
+impl<'a, H: ElementHandler> Read for HtmlRewriter<'a, H> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.buf.len() < buf.len() && !(self.eof && self.buf.parsed == self.buf.end) {
+            if !self.eof {
+                self.eof = !self.buf.fill(&mut *self.underlying)?;
+            }
+            self.parse();
+            if self.buf.end - self.buf.parsed > self.max_buffer {
+                self.skip_markup();
+                self.parse();
+            }
+        }
+        Ok(self.buf.read(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rewriter::{parse_markup, Buffer, Element, ElementHandler, HtmlRewriter, Markup, Rewrite, MIN_READ};
+    use std::cmp;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_buffer_size() {
+        let mut buffer = Buffer::new();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.len(), 0);
+
+        buffer.push_back(&[0; 64]);
+        assert!(!buffer.is_empty());
+        assert_eq!(buffer.len(), 64);
+        let mut buf = [0; 32];
+        buffer.read(&mut buf);
+        assert!(!buffer.is_empty());
+        assert_eq!(buffer.len(), 32);
+        buffer.read(&mut buf);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_buffer_integrity() {
+        let mut buffer = Buffer::new();
+        let mut buf = [0; 8];
+
+        buffer.push_back(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        buffer.push_back(&[10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
+
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 8);
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+
+        buffer.push_back(&[20, 21, 22, 23, 24, 25, 26, 27, 28, 29]);
+
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 8);
+        assert_eq!(buf, [8, 9, 10, 11, 12, 13, 14, 15]);
+
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 8);
+        assert_eq!(buf, [16, 17, 18, 19, 20, 21, 22, 23]);
+
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 6);
+        assert_eq!(buf[..6], [24, 25, 26, 27, 28, 29]);
+
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn test_buffer_input() {
+        let mut buffer = Buffer::new();
+        let mut buf = [0; 8];
+        let mut input = &b"0123456789"[..];
+
+        assert!(buffer.fill(&mut input).unwrap());
+        assert!(buffer.is_empty());
+        buffer.pass_until(4);
+        buffer.push_back(b"ab");
+        buffer.pass_until(6);
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(buffer.input_from(buffer.parsed), b"6789");
+
+        let size = buffer.read(&mut buf[..5]);
+        assert_eq!(size, 5);
+        assert_eq!(buf[..5], b"0123a"[..]);
+        buffer.push_back(b"cd");
+        buffer.pass_until(10);
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 8);
+        assert_eq!(buf, *b"b45cd678");
+        let size = buffer.read(&mut buf);
+        assert_eq!(size, 1);
+        assert_eq!(buf[0], b'9');
+        assert!(buffer.is_empty());
+        assert!(!buffer.fill(&mut input).unwrap());
+    }
+
+    #[test]
+    fn test_long_markup() {
+        //a tag much longer than what is read at once
+        let data = [&b"<p title='"[..], &[b'a'; 50_000][..], b"'><br>"].concat();
+        let mut rewriter = HtmlRewriter::new(Box::new(Cursor::new(&data[..])), Recorder(Vec::new()));
+        let mut res = Vec::new();
+        rewriter.read_to_end(&mut res).unwrap();
+        assert_eq!(res, [&data[..data.len() - 4], b"[<br class=\"x\">]"].concat());
+        assert_eq!(rewriter.handler().0, ["p", "br"]);
+    }
+
+    // reader giving its content a few bytes at a time
+    struct ChunkedReader<'a>(&'a [u8]);
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ::std::io::Error> {
+            let len = cmp::min(cmp::min(buf.len(), 100), self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_max_buffer() {
+        let long = |prefix: &[u8], content: &[u8], suffix: &[u8]| [prefix, &content.repeat(5000), suffix].concat();
+        let cases = [
+            (long(b"<p title='", b"<br>", b"'><br>"), false, vec!["br"]),
+            (long(b"<P TITLE=\"a>", b"<br>", b"\"/><br>"), false, vec!["br"]),
+            (long(b"<!-- ", b"<br>", b" --><br>"), false, vec!["br"]),
+            (long(b"<![CDATA[", b"<br>", b"]]><br>"), false, vec!["br"]),
+            (long(b"<script src='", b"x", b"'>'<br>'</script><br>"), false, vec!["/script", "br"]),
+            (long(b"<script src=a", b" ", b"/>'<br>'</script><br>"), true, vec!["br", "/script", "br"]),
+            (long(b"</div", b" ", b"='a><br>'><br>"), false, vec!["br", "br"]),
+            (long(b"<form action='", b"a", b""), false, vec![]), //never ends
+            (long(b"<form", b" a=b", b""), false, vec![]),
+        ];
+        for (data, xml, expected) in &cases {
+            let mut rewriter = HtmlRewriter::new(Box::new(ChunkedReader(data)), Recorder(Vec::new()))
+                .with_max_buffer(256)
+                .with_xml_syntax(*xml);
+            let mut res = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                match rewriter.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => res.extend_from_slice(&buf[..len]),
+                }
+            }
+            assert_eq!(rewriter.buf.input.len(), 2 * MIN_READ); //never grew
+            assert_eq!(rewriter.handler().0, *expected);
+            let res = String::from_utf8(res).unwrap();
+            assert_eq!(res.matches("[<br class=\"x\">]").count(), expected.iter().filter(|&&name| name == "br").count());
+            assert_eq!(res.replace("[<br class=\"x\">]", "<br>").as_bytes(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_parse_markup() {
+        let tag = |name: &'static [u8], end, attributes: Vec<(&'static [u8], &'static [u8])>| {
+            Markup::Tag(Element {
+                name: name.into(),
+                end,
+                self_closing: false,
+                attributes: attributes.into_iter().map(|(n, v)| (n.into(), v)).collect(),
+            })
+        };
+        let data = b"<FORM action = \"/a>b\" METHOD=post\ndata-x='1' disabled/ method=get>rest";
+        assert_eq!(
+            parse_markup(data),
+            Some((
+                tag(b"form", false, vec![
+                    (b"action", b"/a>b"),
+                    (b"method", b"post"),
+                    (b"data-x", b"1"),
+                    (b"disabled", b""),
+                ]),
+                data.len() - 4
+            ))
+        );
+        for i in 0..data.len() - 4 {
+            assert_eq!(parse_markup(&data[..i]), None); //incomplete tags
+        }
+        assert_eq!(parse_markup(b"</form\n>"), Some((tag(b"form", true, vec![]), 8)));
+        assert_eq!(parse_markup(b"<input value=a/>"), Some((tag(b"input", false, vec![(b"value", b"a/")]), 16)));
+        assert_eq!(
+            parse_markup(b"<br/>"),
+            Some((Markup::Tag(Element { name: (&b"br"[..]).into(), end: false, self_closing: true, attributes: vec![] }), 5))
+        );
+        assert_eq!(parse_markup(b"<!-- <form> -->"), Some((Markup::Comment, 15)));
+        assert_eq!(parse_markup(b"<![CDATA[ a > b <form> ]]>"), Some((Markup::Comment, 26)));
+        assert_eq!(parse_markup(b"<![CDA"), None);
+        assert_eq!(parse_markup(b"<!-->"), Some((Markup::Comment, 5)));
+        assert_eq!(parse_markup(b"<!DOCTYPE html>"), Some((Markup::Comment, 15)));
+        assert_eq!(parse_markup(b"<!-"), None);
+        assert_eq!(parse_markup(b"< form>"), Some((Markup::Text, 1)));
+        assert_eq!(parse_markup(b"<3"), Some((Markup::Text, 1)));
+    }
+
+    struct Recorder(Vec<String>);
+
+    impl ElementHandler for Recorder {
+        fn element(&mut self, element: &Element, rewrite: &mut Rewrite) {
+            let name = String::from_utf8_lossy(element.name()).into_owned();
+            self.0.push(if element.is_end_tag() { format!("/{}", name) } else { name });
+            match (element.name(), element.is_end_tag()) {
+                (b"img", false) => rewrite.add_attribute("alt", "\"&"),
+                (b"br", false) => {
+                    rewrite.insert_before(b"[");
+                    rewrite.insert_after(b"]");
+                    rewrite.add_attribute("class", "x");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_element_handlers() {
+        let data = b"<p><img src=a.png><br/><!-- <br> --><script>'<br>'</script></p>";
+        let handlers = (Recorder(Vec::new()), vec![Recorder(Vec::new())]);
+        let mut rewriter = HtmlRewriter::new(Box::new(Cursor::new(&data[..])), handlers);
+        let mut res = String::new();
+        rewriter.read_to_string(&mut res).unwrap();
+        assert_eq!(
+            res,
+            "<p><img src=a.png alt=\"&quot;&amp;\" alt=\"&quot;&amp;\">\
+[[<br class=\"x\" class=\"x\"/>]]<!-- <br> --><script>'<br>'</script></p>"
+        );
+        let (first, others) = rewriter.handler();
+        assert_eq!(first.0, ["p", "img", "br", "script", "/script", "/p"]);
+        assert_eq!(others[0].0, first.0);
+    }
+}
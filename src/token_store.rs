@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Server-side storage of synchronizer tokens.
+///
+/// When a `TokenStore` is registered with
+/// [`set_token_store`](struct.CsrfFairingBuilder.html#method.set_token_store), tokens are no longer
+/// checked against a cookie, but against the token stored for the current session. This allow to
+/// revoke tokens server-side, for instance when an user log out.
+///
+/// Sessions are identified by the bytes returned by the session extractor configured on the
+/// builder. Expiration dates are in seconds since Unix epoch.
+pub trait TokenStore: Send + Sync {
+    /// Get the token issued to a session, and its expiration date.
+    fn get(&self, session: &[u8]) -> Option<(Vec<u8>, u64)>;
+
+    /// Store the token issued to a session, replacing any previous one.
+    fn set(&self, session: &[u8], token: Vec<u8>, expires: u64);
+
+    /// Revoke the token issued to a session. Next requests from this session will need a new
+    /// token.
+    fn revoke(&self, session: &[u8]);
+
+    /// Revoke all tokens.
+    fn revoke_all(&self);
+}
+
+/// In-memory [TokenStore](trait.TokenStore.html) holding a bounded number of sessions.
+///
+/// Tokens are lost on application restart, and are not shared between several instances of an
+/// application. When full, the least recently used session is evicted. The token of an evicted
+/// session is no longer accepted, so the capacity should be larger than the number of sessions
+/// you expect to be active at once.
+#[derive(Debug)]
+pub struct MemoryTokenStore {
+    capacity: usize,
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    tokens: HashMap<Vec<u8>, (StoredToken, u64)>, //tokens, with the last time they were used
+    order: VecDeque<(Vec<u8>, u64)>,              //sessions by use, outdated entries being skipped
+    uses: u64,
+}
+
+type StoredToken = (Vec<u8>, u64);
+
+impl Sessions {
+    // mark a stored session as the most recently used one
+    fn touch(&mut self, session: &[u8]) {
+        self.uses += 1;
+        if let Some(entry) = self.tokens.get_mut(session) {
+            entry.1 = self.uses;
+            self.order.push_back((session.to_vec(), self.uses));
+        }
+        if self.order.len() > 2 * self.tokens.len() + 16 {
+            let tokens = &self.tokens;
+            self.order.retain(|(session, used)| tokens.get(session).map_or(false, |entry| entry.1 == *used));
+        } //drop outdated entries once they outnumber live ones, so it's amortized constant time
+    }
+
+    fn evict_least_recent(&mut self) {
+        while let Some((session, used)) = self.order.pop_front() {
+            if self.tokens.get(&session).map_or(false, |entry| entry.1 == used) {
+                self.tokens.remove(&session);
+                return;
+            }
+        }
+    }
+}
+
+impl MemoryTokenStore {
+    /// Create a new empty store holding up to 64k sessions.
+    pub fn new() -> Self {
+        Self::with_capacity(64 * 1024)
+    }
+
+    /// Create a new empty store holding at most `capacity` sessions.
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemoryTokenStore {
+            capacity,
+            sessions: Mutex::new(Sessions::default()),
+        }
+    }
+}
+
+impl Default for MemoryTokenStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn get(&self, session: &[u8]) -> Option<(Vec<u8>, u64)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let token = sessions.tokens.get(session).map(|entry| entry.0.clone());
+        sessions.touch(session);
+        token
+    }
+
+    fn set(&self, session: &[u8], token: Vec<u8>, expires: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.tokens.get_mut(session) {
+            Some(entry) => entry.0 = (token, expires),
+            None => {
+                while sessions.tokens.len() >= self.capacity {
+                    sessions.evict_least_recent();
+                }
+                sessions.tokens.insert(session.to_vec(), ((token, expires), 0));
+            }
+        }
+        sessions.touch(session);
+    }
+
+    fn revoke(&self, session: &[u8]) {
+        self.sessions.lock().unwrap().tokens.remove(session);
+    }
+
+    fn revoke_all(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.tokens.clear();
+        sessions.order.clear();
+    }
+}
+
+/// Record of already used single-use tokens.
+///
+/// Routes registered with
+/// [`set_single_use_routes`](struct.CsrfFairingBuilder.html#method.set_single_use_routes) only
+/// accept each token once, the id of tokens submitted to them being recorded here until they
+/// expire. A cache dropping a token before its expiration must reject tokens expiring before it,
+/// otherwise that token could be used once more.
+pub trait ReplayCache: Send + Sync {
+    /// Record the use of a token, valid until `expires` (in seconds since Unix epoch). Return
+    /// false if this token was already used, or can't be told apart from one.
+    fn insert(&self, id: &[u8], expires: u64) -> bool;
+}
+
+/// In-memory [ReplayCache](trait.ReplayCache.html) holding a bounded number of tokens.
+///
+/// Expired tokens are dropped as new ones are inserted. When still full, the tokens closest to
+/// their expiration are evicted, and tokens expiring before them are then rejected, so the
+/// capacity should be larger than the number of single-use submissions you expect during a token
+/// lifetime.
+#[derive(Debug)]
+pub struct MemoryReplayCache {
+    capacity: usize,
+    used: Mutex<UsedTokens>,
+}
+
+#[derive(Debug, Default)]
+struct UsedTokens {
+    ids: HashSet<Vec<u8>>,
+    queue: VecDeque<(Vec<u8>, u64)>, //ids and expiration dates, by expiration date
+    horizon: u64,                    //latest expiration date of tokens evicted before expiring
+}
+
+impl MemoryReplayCache {
+    /// Create a new cache holding at most `capacity` tokens.
+    pub fn new(capacity: usize) -> Self {
+        MemoryReplayCache {
+            capacity,
+            used: Mutex::new(UsedTokens::default()),
+        }
+    }
+}
+
+impl Default for MemoryReplayCache {
+    fn default() -> Self {
+        Self::new(16 * 1024)
+    }
+}
+
+impl ReplayCache for MemoryReplayCache {
+    fn insert(&self, id: &[u8], expires: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let UsedTokens {
+            ref mut ids,
+            ref mut queue,
+            ref mut horizon,
+        } = *used;
+        if expires <= *horizon || ids.contains(id) {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        while queue.front().map_or(false, |(_, expires)| *expires <= now) {
+            if let Some((id, _)) = queue.pop_front() {
+                ids.remove(&id);
+            }
+        } //drop expired tokens, found at the front
+        while ids.len() >= self.capacity {
+            match queue.pop_front() {
+                Some((id, expires)) => {
+                    *horizon = expires.max(*horizon);
+                    ids.remove(&id)
+                }
+                None => break,
+            };
+        } //if still full, evict tokens closest to their expiration
+        if self.capacity > 0 {
+            //tokens are mostly used in the order they were issued, so this is usually at the end
+            let position = queue.len()
+                - queue.iter().rev().take_while(|(_, later)| *later > expires).count();
+            ids.insert(id.to_vec());
+            queue.insert(position, (id.to_vec(), expires));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use token_store::{MemoryReplayCache, MemoryTokenStore, ReplayCache, TokenStore};
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryTokenStore::new();
+        assert!(store.get(b"session").is_none());
+
+        store.set(b"session", vec![1, 2, 3], u64::MAX);
+        store.set(b"other", vec![4, 5, 6], u64::MAX);
+        assert_eq!(store.get(b"session").unwrap(), (vec![1, 2, 3], u64::MAX));
+
+        store.revoke(b"session");
+        assert!(store.get(b"session").is_none());
+        assert!(store.get(b"other").is_some());
+
+        store.revoke_all();
+        assert!(store.get(b"other").is_none());
+    }
+
+    #[test]
+    fn test_memory_store_capacity() {
+        let store = MemoryTokenStore::with_capacity(2);
+        store.set(b"session", vec![1], u64::MAX);
+        store.set(b"other", vec![2], u64::MAX);
+        store.set(b"session", vec![3], u64::MAX); //same session, nothing evicted
+        assert!(store.get(b"other").is_some());
+
+        store.set(b"new", vec![4], u64::MAX); //least recently used session evicted
+        assert!(store.get(b"session").is_none());
+        assert!(store.get(b"other").is_some());
+        store.set(b"last", vec![5], u64::MAX);
+        assert!(store.get(b"new").is_none());
+        assert_eq!(store.get(b"other").unwrap(), (vec![2], u64::MAX));
+
+        for i in 0..100u8 {
+            store.get(b"other"); //repeated uses don't grow the store
+            store.set(&[i], vec![i], u64::MAX);
+        }
+        assert!(store.get(b"other").is_some());
+        assert_eq!(store.sessions.lock().unwrap().tokens.len(), 2);
+        assert!(store.sessions.lock().unwrap().order.len() <= 2 * 2 + 16);
+    }
+
+    #[test]
+    fn test_memory_replay_cache() {
+        let cache = MemoryReplayCache::new(2);
+        assert!(cache.insert(b"first", u64::MAX));
+        assert!(!cache.insert(b"first", u64::MAX)); //replay detected
+        assert!(cache.insert(b"expired", 1));
+        assert!(cache.insert(b"second", u64::MAX)); //expired token evicted
+        assert!(cache.insert(b"expired", 1)); //oldest token evicted
+        assert!(!cache.insert(b"second", u64::MAX));
+        assert!(!cache.insert(b"first", u64::MAX)); //evicted, but can't be told apart from a replay
+    }
+
+    #[test]
+    fn test_memory_replay_cache_horizon() {
+        let cache = MemoryReplayCache::new(1);
+        assert!(cache.insert(b"first", u64::MAX - 10));
+        assert!(cache.insert(b"second", u64::MAX - 1)); //first evicted before expiring
+        assert!(!cache.insert(b"first", u64::MAX - 10));
+        assert!(!cache.insert(b"older", u64::MAX - 20)); //issued before it, may have been used too
+        assert!(cache.insert(b"newer", u64::MAX));
+
+        let cache = MemoryReplayCache::new(2);
+        assert!(cache.insert(b"late", u64::MAX - 10));
+        assert!(cache.insert(b"early", u64::MAX - 20)); //used after a token issued later
+        assert!(cache.insert(b"third", u64::MAX - 5)); //evicts the one closest to expiration
+        assert!(!cache.insert(b"late", u64::MAX - 10));
+        assert!(cache.insert(b"between", u64::MAX - 15)); //horizon only moved to the evicted one
+    }
+}
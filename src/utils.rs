@@ -13,9 +13,218 @@ fn parse_keyvalue(kv: &str) -> Option<(&str, &str)> {
     }
 }
 
+pub fn resolve_uri(base: &str, reference: &str) -> String {
+    //resolve a (possibly relative) reference found in a page served at base into an origin-form
+    //uri (path and query), as a browser would do before sending a request to it
+    let reference = clean_reference(reference);
+    let reference = reference.split('#').next().unwrap_or("").to_owned();
+    let base_path = base.split('?').next().unwrap_or("");
+    let scheme_end = reference.find("://").filter(|&pos| {
+        !reference[..pos].contains(|c| c == '/' || c == '?')
+    });
+    let uri = if let Some(authority) = scheme_end
+        .map(|pos| &reference[pos + 3..])
+        .or_else(|| reference.strip_prefix("//"))
+    {
+        //absolute url, keep only the path and query
+        match authority.find(|c| c == '/' || c == '?') {
+            Some(pos) if authority[pos..].starts_with('?') => format!("/{}", &authority[pos..]),
+            Some(pos) => authority[pos..].to_owned(),
+            None => "/".to_owned(),
+        }
+    } else if reference.is_empty() {
+        base.to_owned()
+    } else if reference.starts_with('/') {
+        reference
+    } else if reference.starts_with('?') {
+        format!("{}{}", base_path, reference)
+    } else {
+        let dir = &base_path[..base_path.rfind('/').map(|pos| pos + 1).unwrap_or(0)];
+        format!("{}{}", dir, reference)
+    };
+
+    //remove dot segments
+    let (path, query) = match uri.find('?') {
+        Some(pos) => uri.split_at(pos),
+        None => (&uri[..], ""),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = path.ends_with('/');
+    for seg in path.split('/').filter(|seg| !seg.is_empty()) {
+        trailing_slash = false;
+        match seg {
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            _ => segments.push(seg),
+        }
+    }
+    let mut res = String::from("/");
+    res.push_str(&segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        res.push('/');
+    }
+    res.push_str(query);
+    normalize_uri(&res)
+}
+
+pub fn normalize_uri(uri: &str) -> String {
+    //percent-encode an uri the same way whether it comes from a page or a request: browsers encode
+    //some characters and not others, and may or may not decode escaped unreserved ones
+    const HEX: &[u8] = b"0123456789ABCDEF";
+    let keep = |c: u8| c.is_ascii_alphanumeric() || b"-._~/?&=:@!$()*+,;".contains(&c);
+    let hex = |c: u8| (c as char).to_digit(16);
+    let bytes = uri.as_bytes();
+    let mut res = String::with_capacity(uri.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match (bytes[i], bytes.get(i + 1).cloned().and_then(hex), bytes.get(i + 2).cloned().and_then(hex)) {
+            (b'%', Some(high), Some(low)) => Some((high * 16 + low) as u8),
+            _ => None,
+        };
+        let c = escaped.unwrap_or(bytes[i]);
+        if keep(c) && (escaped.is_none() || c.is_ascii_alphanumeric() || b"-._~".contains(&c)) {
+            res.push(c as char); //escaped delimiters are kept escaped, as they have another meaning
+        } else {
+            res.push('%');
+            res.push(HEX[(c >> 4) as usize] as char);
+            res.push(HEX[(c & 15) as usize] as char);
+        }
+        i += if escaped.is_some() { 3 } else { 1 };
+    }
+    res
+}
+
+pub fn is_own_origin(reference: &str, origins: &[String]) -> bool {
+    //check if a reference found in a page stays on the same origin, or points to one of origins
+    let reference = clean_reference(reference);
+    let (scheme, rest) = match reference.find(':') {
+        Some(pos) if is_scheme(&reference[..pos]) => (Some(&reference[..pos]), &reference[pos + 1..]),
+        _ => (None, &reference[..]),
+    };
+    let authority = match rest.strip_prefix("//") {
+        Some(rest) => &rest[..rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or(rest.len())],
+        None => return scheme.is_none(), //relative reference, or scheme without authority like mailto:
+    };
+    let authority = &authority[authority.rfind('@').map(|pos| pos + 1).unwrap_or(0)..]; //drop user info
+
+    origins.iter().any(|origin| match origin.find("://") {
+        Some(pos) => {
+            let origin_scheme = &origin[..pos];
+            scheme.map_or(true, |scheme| scheme.eq_ignore_ascii_case(origin_scheme))
+                && strip_default_port(origin_scheme, authority)
+                    .eq_ignore_ascii_case(strip_default_port(origin_scheme, origin[pos + 3..].trim_end_matches('/')))
+        }
+        None => false,
+    })
+}
+
+fn clean_reference(reference: &str) -> String {
+    //get an url from an attribute value as a browser does: decode character references, and
+    //ignore surrounding whitespaces and tabs and newlines, treating backslashes as slashes
+    decode_char_refs(reference)
+        .trim_matches(|c: char| c.is_ascii_whitespace())
+        .chars()
+        .filter(|&c| c != '\t' && c != '\n' && c != '\r')
+        .map(|c| if c == '\\' { '/' } else { c })
+        .collect()
+}
+
+fn decode_char_refs(value: &str) -> String {
+    //decode character references in an attribute value. Only named references of ascii
+    //characters and non breaking space are known, others are kept as is
+    const NAMED: &[(&str, char)] = &[
+        ("amp", '&'),
+        ("colon", ':'),
+        ("sol", '/'),
+        ("bsol", '\\'),
+        ("quest", '?'),
+        ("num", '#'),
+        ("commat", '@'),
+        ("period", '.'),
+        ("Tab", '\t'),
+        ("NewLine", '\n'),
+        ("nbsp", '\u{a0}'),
+        ("excl", '!'),
+        ("quot", '"'),
+        ("dollar", '$'),
+        ("percnt", '%'),
+        ("apos", '\''),
+        ("lpar", '('),
+        ("rpar", ')'),
+        ("ast", '*'),
+        ("plus", '+'),
+        ("comma", ','),
+        ("semi", ';'),
+        ("lt", '<'),
+        ("equals", '='),
+        ("gt", '>'),
+        ("lsqb", '['),
+        ("rsqb", ']'),
+        ("Hat", '^'),
+        ("lowbar", '_'),
+        ("grave", '`'),
+        ("lcub", '{'),
+        ("verbar", '|'),
+        ("rcub", '}'),
+    ];
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('&') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let (decoded, len) = if let Some(num) = rest.strip_prefix('#') {
+            let (radix, digits) = match num.strip_prefix(|c| c == 'x' || c == 'X') {
+                Some(hex) => (16, hex),
+                None => (10, num),
+            };
+            let end = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+            let prefix = num.len() - digits.len() + 1;
+            let semicolon = if digits[end..].starts_with(';') { 1 } else { 0 };
+            match u32::from_str_radix(&digits[..end], radix).ok().and_then(std::char::from_u32) {
+                Some(c) => (Some(c), prefix + end + semicolon),
+                None => (None, 0),
+            }
+        } else {
+            match NAMED.iter().find(|(name, _)| {
+                rest.starts_with(name) && rest[name.len()..].starts_with(';')
+            }) {
+                Some((name, c)) => (Some(*c), name.len() + 1),
+                None => (None, 0),
+            }
+        };
+        match decoded {
+            Some(c) => res.push(c),
+            None => res.push('&'),
+        }
+        rest = &rest[len..];
+    }
+    res.push_str(rest);
+    res
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+fn strip_default_port<'a>(scheme: &str, authority: &'a str) -> &'a str {
+    let port = if scheme.eq_ignore_ascii_case("https") {
+        ":443"
+    } else if scheme.eq_ignore_ascii_case("http") {
+        ":80"
+    } else {
+        return authority;
+    };
+    authority.strip_suffix(port).unwrap_or(authority)
+}
+
 #[cfg(test)]
 mod tests {
-    use utils::{parse_args, parse_keyvalue};
+    use utils::{is_own_origin, normalize_uri, parse_args, parse_keyvalue, resolve_uri};
     #[test]
     fn test_parse_keyvalue() {
         assert_eq!(
@@ -46,4 +255,62 @@ mod tests {
         assert_eq!(it.next().unwrap(), ("key3", ""));
         assert!(it.next().is_none());
     }
+
+    #[test]
+    fn test_resolve_uri() {
+        assert_eq!(resolve_uri("/path/page?query=1", ""), "/path/page?query=1");
+        assert_eq!(resolve_uri("/path/page", "/absolute"), "/absolute");
+        assert_eq!(resolve_uri("/path/page", "relative"), "/path/relative");
+        assert_eq!(resolve_uri("/path/", "relative?a=b&amp;c=d"), "/path/relative?a=b&c=d");
+        assert_eq!(resolve_uri("/path/page", "?other"), "/path/page?other");
+        assert_eq!(resolve_uri("/path/page", "."), "/path/");
+        assert_eq!(resolve_uri("/path/sub/page", "../other/./x#frag"), "/path/other/x");
+        assert_eq!(resolve_uri("/", "../.."), "/");
+        assert_eq!(resolve_uri("/path/page", "https://example.com/target?q"), "/target?q");
+        assert_eq!(resolve_uri("/path/page", "//example.com"), "/");
+        assert_eq!(resolve_uri("/path/page", "http://example.com?q"), "/?q");
+        assert_eq!(resolve_uri("/path/page", "&#47;x&sol;y?a=1&amp;b=&lt;2&gt;"), "/x/y?a=1&b=%3C2%3E");
+        assert_eq!(resolve_uri("/path/page", " my page\t?q=caf\u{e9} "), "/path/my%20page?q=caf%C3%A9");
+    }
+
+    #[test]
+    fn test_normalize_uri() {
+        assert_eq!(normalize_uri("/path/page?a=b&c=d"), "/path/page?a=b&c=d");
+        assert_eq!(normalize_uri("/my page/caf\u{e9}"), "/my%20page/caf%C3%A9");
+        assert_eq!(normalize_uri("/my%20page/caf%c3%a9"), "/my%20page/caf%C3%A9");
+        assert_eq!(normalize_uri("/%7euser/%41?q=%27'"), "/~user/A?q=%27%27");
+        assert_eq!(normalize_uri("/a%2Fb?x=%26&y=100%"), "/a%2Fb?x=%26&y=100%25"); //delimiters stay escaped
+    }
+
+    #[test]
+    fn test_is_own_origin() {
+        let origins = ["https://example.com".to_owned(), "http://other.org:8000/".to_owned()];
+        for &reference in &["", "/path", "relative?q", "?q", "#frag", "  /path  "] {
+            assert!(is_own_origin(reference, &[]), "{}", reference);
+        }
+        for &reference in &[
+            "https://example.com/pay",
+            "HTTPS://Example.com:443",
+            "//example.com/path",
+            "http://other.org:8000?q",
+        ] {
+            assert!(is_own_origin(reference, &origins), "{}", reference);
+        }
+        for &reference in &[
+            "https://evil.com/pay",
+            "http://example.com/",
+            "https://example.com.evil.com/",
+            "https://example.com@evil.com/",
+            "//evil.com",
+            "/\\evil.com",
+            "/\t/evil.com",
+            "http://other.org/",
+            "mailto:someone@example.com",
+            "javascript:void(0)",
+            "&#x2F;&#47evil.com",
+            "https&colon;//evil.com",
+        ] {
+            assert!(!is_own_origin(reference, &origins), "{}", reference);
+        }
+    }
 }